@@ -0,0 +1,93 @@
+// ANSI-like colour escapes for `print!`.
+//
+// Only SGR sequences (`ESC [ <n>;... m`) are understood. They are passed
+// through unchanged to serial and the boot log, where terminals and
+// `less -R` show them, and turned into `SetAttribute` calls on ConOut.
+// A sequence must not be split across `print!` arguments.
+
+use crate::uefi::*;
+
+pub const RESET: &str = "\x1b[0m";
+pub const BOLD: &str = "\x1b[1m";
+pub const RED: &str = "\x1b[31m";
+pub const GREEN: &str = "\x1b[32m";
+pub const YELLOW: &str = "\x1b[33m";
+
+/// The attribute `RESET` returns to, matching the firmware's default
+pub const DEFAULT_ATTRIBUTE: usize = EFI_LIGHTGRAY | EFI_BACKGROUND_BLACK;
+
+/// EFI colours in the order of the ANSI colour numbers 0-7
+const EFI_COLOURS: [usize; 8] = [
+    EFI_BLACK,
+    EFI_RED,
+    EFI_GREEN,
+    EFI_BROWN,
+    EFI_BLUE,
+    EFI_MAGENTA,
+    EFI_CYAN,
+    EFI_LIGHTGRAY,
+];
+
+pub enum Segment<'a> {
+    Text(&'a str),
+    /// The parameters of an SGR sequence, e.g. `1;31`
+    Sgr(&'a str),
+}
+
+/// Splits `s` into text and SGR sequences. Escapes of other kinds are
+/// left in the text.
+pub fn segments(s: &str) -> impl Iterator<Item = Segment<'_>> {
+    let mut rest = s;
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        if let Some(params) = rest.strip_prefix("\x1b[") {
+            let end = params
+                .find(|c: char| !(c.is_ascii_digit() || c == ';'))
+                .filter(|&end| params[end..].starts_with('m'));
+            if let Some(end) = end {
+                rest = &params[end + 1..];
+                return Some(Segment::Sgr(&params[..end]));
+            }
+        }
+        // Never empty: a lone ESC is text
+        let end = rest[1..].find('\x1b').map_or(rest.len(), |end| end + 1);
+        let (text, tail) = rest.split_at(end);
+        rest = tail;
+        Some(Segment::Text(text))
+    })
+}
+
+/// Applies the SGR parameters `params` to the EFI text attribute
+/// `attribute`. Unsupported parameters are ignored.
+pub fn apply_sgr(attribute: usize, params: &str) -> usize {
+    let mut foreground = attribute & 0x0f;
+    let mut background = attribute & 0x70;
+    for param in params.split(';') {
+        // An empty parameter means 0
+        match param.parse::<usize>().unwrap_or(0) {
+            0 => {
+                foreground = DEFAULT_ATTRIBUTE & 0x0f;
+                background = DEFAULT_ATTRIBUTE & 0x70;
+            }
+            1 => foreground |= EFI_BRIGHT,
+            22 => foreground &= !EFI_BRIGHT,
+            n @ 30..=37 => foreground = foreground & EFI_BRIGHT | EFI_COLOURS[n - 30],
+            39 => foreground = DEFAULT_ATTRIBUTE & 0x0f,
+            n @ 40..=47 => background = EFI_COLOURS[n - 40] << 4,
+            49 => background = DEFAULT_ATTRIBUTE & 0x70,
+            n @ 90..=97 => foreground = EFI_COLOURS[n - 90] | EFI_BRIGHT,
+            _ => {}
+        }
+    }
+    foreground | background
+}
+
+/// Removes SGR sequences, for output that cannot show them.
+pub fn text(s: &str) -> impl Iterator<Item = &str> {
+    segments(s).filter_map(|segment| match segment {
+        Segment::Text(text) => Some(text),
+        Segment::Sgr(_) => None,
+    })
+}
@@ -0,0 +1,174 @@
+use crate::boot_info::CMDLINE_MAX;
+use crate::config::{BootConfig, BootEntry};
+use crate::cstr16::CStr16;
+use crate::logger::LogFilter;
+use crate::protocols::{EfiLoadedImageProtocol, EfiShellParametersProtocol};
+use crate::uefi::{EfiBootServices, EfiHandle};
+use heapless::consts::{U128, U512};
+use heapless::String;
+use log::warn;
+
+/// Arguments given to `uefi_lemola_os.efi`, either by the UEFI shell or
+/// as the optional data of a Boot#### entry.
+///
+/// Loader flags are consumed here and take precedence over `\lemola.cfg`;
+/// every other argument, as well as everything after `--`, is appended to
+/// the kernel command line of the booted entry.
+///
+/// ```text
+/// uefi_lemola_os.efi kernel=\foo.elf verbose log=debug resolution=1280x800 -- loglevel=4
+/// ```
+#[derive(Default)]
+pub struct LoaderArgs {
+    pub kernel_path: Option<String<U128>>,
+    pub verbose: bool,
+    pub dump_memmap: bool,
+    pub resolution: Option<(u32, u32)>,
+    pub log_filter: Option<LogFilter>,
+    pub cmdline: String<U512>,
+    forward_all: bool,
+}
+
+impl LoaderArgs {
+    /// Collects the arguments of the image `image_handle`.
+    ///
+    /// EFI_SHELL_PARAMETERS_PROTOCOL is preferred because the shell has
+    /// already split and unquoted argv; otherwise `LoadedImage.load_options`
+    /// is decoded as a UTF-16 command line.
+    pub fn from_image(boot_services: &EfiBootServices, image_handle: EfiHandle) -> Self {
+        let mut args = Self::default();
+        if let Ok(shell) = boot_services.handle_protocol::<EfiShellParametersProtocol>(image_handle)
+        {
+            // argv[0] is the image itself
+            let mut index = 1;
+            while let Some(arg) = shell.arg(index) {
+                args.parse_utf16(arg);
+                index += 1;
+            }
+            return args;
+        }
+
+        let loaded_image =
+            match boot_services.handle_protocol::<EfiLoadedImageProtocol>(image_handle) {
+                Ok(loaded_image) => loaded_image,
+                Err(status) => {
+                    warn!("LoadedImage is not available: {:?}", status);
+                    return args;
+                }
+            };
+        let load_options = loaded_image.load_options();
+        let load_options = match load_options.iter().position(|&c| c == 0) {
+            Some(nul) => &load_options[..nul],
+            None => load_options,
+        };
+        let mut line: String<U512> = String::new();
+        for c in core::char::decode_utf16(load_options.iter().copied()) {
+            match c {
+                Ok(c) if line.push(c).is_ok() => {}
+                // Boot#### optional data is not necessarily a string
+                _ => {
+                    warn!("ignoring load options which are not a UTF-16 string");
+                    return args;
+                }
+            }
+        }
+        for (i, arg) in line.split_whitespace().enumerate() {
+            // Boot managers and the shell pass the image path as the first word
+            if i == 0 && ends_with_ignore_ascii_case(arg, ".efi") {
+                continue;
+            }
+            args.parse_arg(arg);
+        }
+        args
+    }
+
+    fn parse_utf16(&mut self, arg: &CStr16) {
+        let mut buf: String<U512> = String::new();
+        for c in arg.chars() {
+            if buf.push(c).is_err() {
+                warn!("argument too long, truncated");
+                break;
+            }
+        }
+        self.parse_arg(buf.as_str());
+    }
+
+    pub fn parse_arg(&mut self, arg: &str) {
+        if self.forward_all {
+            self.push_cmdline(arg);
+            return;
+        }
+        match arg.split_once('=') {
+            None if arg == "--" => self.forward_all = true,
+            None if arg == "verbose" => self.verbose = true,
+            None if arg == "dump_memmap" => self.dump_memmap = true,
+            Some(("kernel", path)) => {
+                let mut kernel_path = String::new();
+                match kernel_path.push_str(path) {
+                    Ok(()) => self.kernel_path = Some(kernel_path),
+                    Err(()) => warn!("kernel path too long: {}", path),
+                }
+            }
+            Some(("resolution", resolution)) => match parse_resolution(resolution) {
+                Some(resolution) => self.resolution = Some(resolution),
+                None => warn!("invalid resolution: {}", resolution),
+            },
+            Some(("log", spec)) => match LogFilter::parse(spec) {
+                Some(filter) => self.log_filter = Some(filter),
+                None => warn!("invalid log filter: {}", spec),
+            },
+            _ => self.push_cmdline(arg),
+        }
+    }
+
+    /// Overrides the global settings of `config`.
+    pub fn apply_to_config(&self, config: &mut BootConfig) {
+        config.verbose |= self.verbose;
+        config.dump_memmap |= self.dump_memmap;
+        if self.resolution.is_some() {
+            config.resolution = self.resolution;
+        }
+        if let Some(filter) = &self.log_filter {
+            config.log_filter = filter.clone();
+        }
+    }
+
+    /// Overrides the kernel of `entry` and appends the forwarded arguments
+    /// to its command line. Chainload entries are left alone: their
+    /// command line is the load options of another application.
+    pub fn apply_to_entry(&self, entry: &mut BootEntry) {
+        if entry.chainload.is_some() {
+            return;
+        }
+        if let Some(kernel_path) = &self.kernel_path {
+            entry.kernel = kernel_path.clone();
+            // The digest was for the old kernel; the new one's detached
+            // digest applies instead
+            entry.sha256 = None;
+        }
+        if entry.append_cmdline(self.cmdline.as_str()).is_err() {
+            warn!("kernel command line too long, dropped: {}", self.cmdline);
+        }
+    }
+
+    fn push_cmdline(&mut self, arg: &str) {
+        let separator = if self.cmdline.is_empty() { "" } else { " " };
+        if self.cmdline.len() + separator.len() + arg.len() > CMDLINE_MAX {
+            warn!("kernel command line too long, dropped: {}", arg);
+            return;
+        }
+        self.cmdline.push_str(separator).unwrap();
+        self.cmdline.push_str(arg).unwrap();
+    }
+}
+
+/// Parses `<width>x<height>`, e.g. `1280x800`.
+pub fn parse_resolution(s: &str) -> Option<(u32, u32)> {
+    let (horizontal, vertical) = s.split_once('x')?;
+    Some((horizontal.parse().ok()?, vertical.parse().ok()?))
+}
+
+fn ends_with_ignore_ascii_case(s: &str, suffix: &str) -> bool {
+    s.len() >= suffix.len()
+        && s.as_bytes()[s.len() - suffix.len()..].eq_ignore_ascii_case(suffix.as_bytes())
+}
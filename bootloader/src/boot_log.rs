@@ -0,0 +1,136 @@
+use crate::protocols::{EfiFileProtocol, FileAttributes, OpenMode};
+use crate::uefi::*;
+use crate::uefi_utils::{delete_file, path_with_suffix, read_file, write_all, write_file};
+use core::cell::{Cell, UnsafeCell};
+use core::fmt;
+
+/// Everything the loader printed during the last boot, written right
+/// before the kernel is started.
+pub const BOOT_LOG_PATH: &str = "\\lemola_boot.log";
+/// The log of the boot before is rotated to `<BOOT_LOG_PATH>.old`.
+pub const ROTATED_SUFFIX: &str = ".old";
+
+const BOOT_LOG_SIZE: usize = 128 * 1024;
+
+/// Ring buffer of the output of `print!`. Once full, the oldest output
+/// is overwritten.
+struct BootLog {
+    buf: UnsafeCell<[u8; BOOT_LOG_SIZE]>,
+    /// Bytes appended so far, including overwritten ones
+    written: Cell<usize>,
+}
+
+unsafe impl Sync for BootLog {}
+
+static BOOT_LOG: BootLog = BootLog {
+    buf: UnsafeCell::new([0; BOOT_LOG_SIZE]),
+    written: Cell::new(0),
+};
+
+pub fn append(s: &str) {
+    let buf = unsafe { &mut *BOOT_LOG.buf.get() };
+    let mut written = BOOT_LOG.written.get();
+    for chunk in s.as_bytes().chunks(BOOT_LOG_SIZE) {
+        let start = written % BOOT_LOG_SIZE;
+        let (head, tail) = chunk.split_at(chunk.len().min(BOOT_LOG_SIZE - start));
+        buf[start..start + head.len()].copy_from_slice(head);
+        buf[..tail.len()].copy_from_slice(tail);
+        written += chunk.len();
+    }
+    BOOT_LOG.written.set(written);
+}
+
+/// Formats into the log only, for records below the console's level.
+pub fn append_fmt(args: fmt::Arguments) {
+    struct Appender;
+    impl fmt::Write for Appender {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            append(s);
+            Ok(())
+        }
+    }
+    let _ = fmt::Write::write_fmt(&mut Appender, args);
+}
+
+/// The log in order, split in two where the ring buffer wraps around.
+pub fn contents() -> (&'static [u8], &'static [u8]) {
+    let buf = unsafe { &*BOOT_LOG.buf.get() };
+    let written = BOOT_LOG.written.get();
+    if written <= BOOT_LOG_SIZE {
+        return (&buf[..written], &[]);
+    }
+    let start = written % BOOT_LOG_SIZE;
+    (&buf[start..], &buf[..start])
+}
+
+/// Copies the end of the log, at most its last `lines` lines, into `buf`
+/// and returns it.
+pub fn tail(buf: &mut [u8], lines: usize) -> &str {
+    let (older, newer) = contents();
+    let len = (older.len() + newer.len()).min(buf.len());
+    let mut skip = older.len() + newer.len() - len;
+    let mut filled = 0;
+    for part in [older, newer] {
+        let from = skip.min(part.len());
+        skip -= from;
+        let part = &part[from..];
+        buf[filled..filled + part.len()].copy_from_slice(part);
+        filled += part.len();
+    }
+    let text = &buf[..len];
+    // The last line ends with a newline, which does not count
+    let start = text
+        .iter()
+        .enumerate()
+        .rev()
+        .skip(1)
+        .filter(|&(_, &byte)| byte == b'\n')
+        .nth(lines.saturating_sub(1))
+        .map_or(0, |(i, _)| i + 1);
+    // A cut through a UTF-8 sequence, if the buffer was too small
+    let start = start
+        + text[start..]
+            .iter()
+            .take_while(|&&byte| byte & 0xc0 == 0x80)
+            .count();
+    core::str::from_utf8(&text[start..]).unwrap_or("")
+}
+
+/// Writes the log to `BOOT_LOG_PATH`, keeping the previous one.
+pub fn save(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+) -> Result<(), EfiStatusCode> {
+    rotate(boot_services, root_dir)?;
+    let file = root_dir.open(
+        BOOT_LOG_PATH,
+        OpenMode::EfiFiileModeCreate,
+        FileAttributes::EfiFileArchive,
+    )?;
+    let (older, newer) = contents();
+    let result = write_all(file, older).and_then(|()| write_all(file, newer));
+    let status = file.close();
+    result?;
+    if status.is_err() {
+        return Err(status);
+    }
+    Ok(())
+}
+
+/// Moves the existing log to `<BOOT_LOG_PATH>.old`. FAT has no rename
+/// through EfiFileProtocol short of SetInfo, so it is copied instead.
+fn rotate(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+) -> Result<(), EfiStatusCode> {
+    let previous = match read_file(boot_services, root_dir, BOOT_LOG_PATH) {
+        Ok(previous) => previous,
+        Err(EfiStatusCode::EfiNotFound) => return Ok(()),
+        Err(status) => return Err(status),
+    };
+    let rotated_path = path_with_suffix(BOOT_LOG_PATH, ROTATED_SUFFIX).unwrap();
+    let result = write_file(root_dir, rotated_path.as_str(), previous);
+    boot_services.free_pool(previous.as_mut_ptr());
+    result?;
+    delete_file(root_dir, BOOT_LOG_PATH)
+}
@@ -0,0 +1,128 @@
+use crate::protocols::*;
+use crate::signature::{check_signature, SignatureError, SignaturePolicy};
+use crate::uefi::*;
+use crate::uefi_utils::read_file;
+use core::fmt;
+use core::mem::size_of;
+
+#[derive(Debug)]
+pub enum ChainloadError {
+    Efi(EfiStatusCode),
+    Signature(SignatureError),
+}
+
+impl From<EfiStatusCode> for ChainloadError {
+    fn from(status: EfiStatusCode) -> Self {
+        ChainloadError::Efi(status)
+    }
+}
+
+impl fmt::Display for ChainloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainloadError::Efi(status) => write!(f, "{:?}", status),
+            ChainloadError::Signature(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Loads the EFI application at `path` on our ESP, e.g. the UEFI shell,
+/// and runs it with `load_options` as its command line.
+///
+/// The image is checked against its detached signature like the kernel,
+/// so `SignaturePolicy::Enforce` cannot be bypassed with a chainload
+/// entry.
+///
+/// Returns the exit status of the application once it returns.
+pub fn chainload(
+    boot_services: &EfiBootServices,
+    image_handle: EfiHandle,
+    root_dir: &EfiFileProtocol,
+    path: &str,
+    load_options: &str,
+    signature_policy: SignaturePolicy,
+) -> Result<EfiStatus, ChainloadError> {
+    let file = read_file(boot_services, root_dir, path)?;
+    if let Err(err) = check_signature(boot_services, root_dir, path, file, signature_policy) {
+        boot_services.free_pool(file.as_mut_ptr());
+        return Err(ChainloadError::Signature(err));
+    }
+    let child = file_device_path(boot_services, image_handle, path).and_then(|device_path| {
+        let child = boot_services.load_image(image_handle, device_path.cast(), file);
+        boot_services.free_pool(device_path);
+        child
+    });
+    boot_services.free_pool(file.as_mut_ptr());
+    let child = child?;
+
+    let options = match set_load_options(boot_services, child, load_options) {
+        Ok(options) => options,
+        Err(status) => {
+            boot_services.unload_image(child);
+            return Err(status.into());
+        }
+    };
+    let status = boot_services.start_image(child);
+    boot_services.free_pool(options);
+    Ok(status)
+}
+
+/// Builds the device path of the device we were loaded from followed by a
+/// file path node for `path`, in a pool buffer.
+fn file_device_path(
+    boot_services: &EfiBootServices,
+    image_handle: EfiHandle,
+    path: &str,
+) -> Result<*mut u8, EfiStatusCode> {
+    let loaded_image = boot_services.handle_protocol::<EfiLoadedImageProtocol>(image_handle)?;
+    let device =
+        boot_services.handle_protocol::<EfiDevicePathProtocol>(loaded_image.device_handle)?;
+    let device_size = device.size();
+    let header_size = size_of::<EfiDevicePathProtocol>();
+    let node_size = header_size + (path.encode_utf16().count() + 1) * size_of::<u16>();
+    let node_length = u16::try_from(node_size).map_err(|_| EfiStatusCode::EfiBadBufferSize)?;
+
+    let buf = boot_services.allocate_pool(
+        MemoryType::EfiLoaderData,
+        device_size + node_size + header_size,
+    )?;
+    unsafe {
+        let device = device as *const EfiDevicePathProtocol;
+        core::ptr::copy_nonoverlapping(device.cast::<u8>(), buf, device_size);
+        let node = buf.add(device_size);
+        node.cast::<EfiDevicePathProtocol>()
+            .write_unaligned(EfiDevicePathProtocol::new(
+                MEDIA_DEVICE_PATH,
+                MEDIA_FILEPATH_DP,
+                node_length,
+            ));
+        let name = node.add(header_size).cast::<u16>();
+        for (i, c) in path.encode_utf16().chain(Some(0)).enumerate() {
+            name.add(i).write_unaligned(c);
+        }
+        node.add(node_size)
+            .cast::<EfiDevicePathProtocol>()
+            .write_unaligned(EfiDevicePathProtocol::end());
+    }
+    Ok(buf)
+}
+
+/// Passes `load_options` to `child` as a NUL terminated UCS-2 string.
+///
+/// Returns the pool buffer holding them, which must outlive the child.
+fn set_load_options(
+    boot_services: &EfiBootServices,
+    child: EfiHandle,
+    load_options: &str,
+) -> Result<*mut u8, EfiStatusCode> {
+    let loaded_image = boot_services.handle_protocol_mut::<EfiLoadedImageProtocol>(child)?;
+    let size = (load_options.encode_utf16().count() + 1) * size_of::<u16>();
+    let buf = boot_services.allocate_pool(MemoryType::EfiLoaderData, size)?;
+    let options = buf.cast::<u16>();
+    for (i, c) in load_options.encode_utf16().chain(Some(0)).enumerate() {
+        unsafe { options.add(i).write_unaligned(c) };
+    }
+    loaded_image.load_options = buf as *const _;
+    loaded_image.load_options_size = size as u32;
+    Ok(buf)
+}
@@ -0,0 +1,408 @@
+use crate::args::parse_resolution;
+use crate::boot_info::CMDLINE_MAX;
+use crate::integrity::{parse_sha256, Sha256Digest};
+use crate::loader::{KERNEL_WINDOW_END, KERNEL_WINDOW_START};
+use crate::logger::LogFilter;
+use crate::paging::HUGE_PAGE_SIZE;
+use crate::protocols::EfiFileProtocol;
+use crate::signature::SignaturePolicy;
+use crate::uefi::{EfiBootServices, EfiStatusCode};
+use crate::uefi_utils::read_file;
+use core::fmt;
+use heapless::consts::{U128, U16, U512, U64, U8};
+use heapless::{String, Vec};
+use log::{error, warn};
+
+pub const CONFIG_PATH: &str = "\\lemola.cfg";
+pub const DEFAULT_KERNEL_PATH: &str = "\\kernel.elf";
+pub const DEFAULT_TIMEOUT: u32 = 5;
+pub const DEFAULT_KERNEL_STACK_SIZE: usize = 64 * 1024;
+
+/// A kernel the loader can boot.
+#[derive(Debug, Clone)]
+pub struct BootEntry {
+    pub title: String<U64>,
+    pub kernel: String<U128>,
+    pub ramdisk: Option<String<U128>>,
+    pub cmdline: String<U512>,
+    /// Expected SHA-256 of the kernel file
+    pub sha256: Option<Sha256Digest>,
+    /// EFI application to run instead of the kernel, with `cmdline` as
+    /// its load options
+    pub chainload: Option<String<U128>>,
+}
+
+#[derive(Debug)]
+pub struct CmdlineTooLong;
+
+impl BootEntry {
+    pub fn append_cmdline(&mut self, arg: &str) -> Result<(), CmdlineTooLong> {
+        if arg.is_empty() {
+            return Ok(());
+        }
+        let separator = if self.cmdline.is_empty() { "" } else { " " };
+        if self.cmdline.len() + separator.len() + arg.len() > CMDLINE_MAX {
+            return Err(CmdlineTooLong);
+        }
+        self.cmdline.push_str(separator).unwrap();
+        self.cmdline.push_str(arg).unwrap();
+        Ok(())
+    }
+}
+
+impl Default for BootEntry {
+    fn default() -> Self {
+        Self {
+            title: String::from("default"),
+            kernel: String::from(DEFAULT_KERNEL_PATH),
+            ramdisk: None,
+            cmdline: String::new(),
+            sha256: None,
+            chainload: None,
+        }
+    }
+}
+
+/// Contents of `\lemola.cfg`.
+///
+/// ```text
+/// # global defaults, inherited by the entries below
+/// timeout = 5
+/// default = debug
+/// resolution = 1280x800
+/// kernel_stack = 128K
+/// kaslr = off
+/// # relocatable kernels are loaded at or above this 2 MiB aligned address
+/// kernel_base = 0xffffffff80000000
+/// signature = enforce
+/// log = info,loader=debug
+/// dump_memmap = on
+/// cmdline = loglevel=4
+///
+/// [release]
+/// kernel = \kernel.elf
+/// # or put the output of sha256sum in \kernel.elf.sha256
+/// sha256 = 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+///
+/// [debug]
+/// # gzip compressed kernels are decompressed by the loader
+/// kernel = \kernel-debug.elf.gz
+/// ramdisk = \initrd.tar
+/// cmdline = loglevel=7 console=serial
+///
+/// [UEFI shell]
+/// chainload = \EFI\tools\shell.efi
+/// cmdline = -nostartup
+/// ```
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    pub entries: Vec<BootEntry, U8>,
+    pub default_entry: usize,
+    /// Seconds before the default entry is booted
+    pub timeout: u32,
+    pub resolution: Option<(u32, u32)>,
+    pub verbose: bool,
+    /// Size in bytes of the stack the kernel is entered on
+    pub kernel_stack_size: usize,
+    /// Lowest address relocatable kernels are loaded at
+    pub kernel_base: u64,
+    /// Load relocatable kernels at a random address
+    pub kaslr: bool,
+    /// What to do about kernels and ramdisks without a valid signature
+    pub signature_policy: SignaturePolicy,
+    /// Which loader log records are printed
+    pub log_filter: LogFilter,
+    /// Save the final memory map as CSV to `\memmap`
+    pub dump_memmap: bool,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        let mut entries = Vec::new();
+        entries.push(BootEntry::default()).unwrap();
+        Self {
+            entries,
+            default_entry: 0,
+            timeout: DEFAULT_TIMEOUT,
+            resolution: None,
+            verbose: false,
+            kernel_stack_size: DEFAULT_KERNEL_STACK_SIZE,
+            kernel_base: KERNEL_WINDOW_START,
+            kaslr: true,
+            signature_policy: SignaturePolicy::Warn,
+            log_filter: LogFilter::default(),
+            dump_memmap: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub line: usize,
+    pub kind: ConfigErrorKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum ConfigErrorKind {
+    NotUtf8,
+    MissingEquals,
+    UnknownKey(String<U64>),
+    InvalidValue(String<U64>),
+    ValueTooLong(String<U64>),
+    TooManyEntries,
+    UnknownDefault(String<U64>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", CONFIG_PATH, self.line)?;
+        match &self.kind {
+            ConfigErrorKind::NotUtf8 => write!(f, "file is not valid UTF-8"),
+            ConfigErrorKind::MissingEquals => write!(f, "expected `key = value` or `[title]`"),
+            ConfigErrorKind::UnknownKey(key) => write!(f, "unknown key `{}`", key),
+            ConfigErrorKind::InvalidValue(key) => write!(f, "invalid value for `{}`", key),
+            ConfigErrorKind::ValueTooLong(key) => write!(f, "value of `{}` is too long", key),
+            ConfigErrorKind::TooManyEntries => write!(f, "too many entries, ignoring the rest"),
+            ConfigErrorKind::UnknownDefault(default) => {
+                write!(f, "default entry `{}` does not exist", default)
+            }
+        }
+    }
+}
+
+fn truncated<N: heapless::ArrayLength<u8>>(s: &str) -> String<N> {
+    let mut truncated = String::new();
+    for c in s.chars() {
+        if truncated.push(c).is_err() {
+            break;
+        }
+    }
+    truncated
+}
+
+fn set<N: heapless::ArrayLength<u8>>(
+    dest: &mut String<N>,
+    key: &str,
+    value: &str,
+) -> Result<(), ConfigErrorKind> {
+    dest.clear();
+    dest.push_str(value)
+        .map_err(|_| ConfigErrorKind::ValueTooLong(truncated(key)))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "yes" | "true" | "on" => Some(true),
+        "0" | "no" | "false" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a 2 MiB aligned address in the kernel window, in hex with a
+/// `0x` prefix.
+fn parse_kernel_base(value: &str) -> Option<u64> {
+    let digits = value.strip_prefix("0x").or(value.strip_prefix("0X"))?;
+    u64::from_str_radix(digits, 16)
+        .ok()
+        .filter(|base| base.is_multiple_of(HUGE_PAGE_SIZE as u64))
+        .filter(|base| (KERNEL_WINDOW_START..KERNEL_WINDOW_END).contains(base))
+}
+
+/// Parses a size in bytes with an optional `K` or `M` suffix.
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, unit) = match value.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&value[..i], 1024),
+        (i, 'M') | (i, 'm') => (&value[..i], 1024 * 1024),
+        _ => (value, 1),
+    };
+    number.trim().parse::<usize>().ok()?.checked_mul(unit)
+}
+
+impl BootConfig {
+    /// Parses `text`, skipping lines with errors.
+    ///
+    /// Every error is returned together with its 1-based line number so
+    /// that a typo does not make the whole file unusable.
+    pub fn parse(text: &str) -> (Self, Vec<ConfigError, U16>) {
+        let mut config = Self::default();
+        config.entries.clear();
+        let mut errors = Vec::new();
+        // Keys before the first `[title]` are defaults for every entry
+        let mut defaults = BootEntry::default();
+        let mut default_title: Option<(usize, String<U64>)> = None;
+        let mut skip_entry = false;
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut error = |kind| {
+                let _ = errors.push(ConfigError {
+                    line: line_number,
+                    kind,
+                });
+            };
+
+            if let Some(title) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let mut entry = defaults.clone();
+                entry.title = truncated(title.trim());
+                skip_entry = config.entries.push(entry).is_err();
+                if skip_entry {
+                    error(ConfigErrorKind::TooManyEntries);
+                }
+                continue;
+            }
+            if skip_entry {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    error(ConfigErrorKind::MissingEquals);
+                    continue;
+                }
+            };
+            let in_entry = !config.entries.is_empty();
+            let entry = match config.entries.last_mut() {
+                Some(entry) => entry,
+                None => &mut defaults,
+            };
+            let result = match key {
+                "kernel" => set(&mut entry.kernel, key, value),
+                "ramdisk" => {
+                    let mut ramdisk = String::new();
+                    let result = set(&mut ramdisk, key, value);
+                    entry.ramdisk = Some(ramdisk).filter(|ramdisk| !ramdisk.is_empty());
+                    result
+                }
+                "cmdline" => set(&mut entry.cmdline, key, value),
+                "chainload" => {
+                    let mut path = String::new();
+                    let result = set(&mut path, key, value);
+                    entry.chainload = Some(path).filter(|path| !path.is_empty());
+                    result
+                }
+                "sha256" => parse_sha256(value)
+                    .map(|digest| entry.sha256 = Some(digest))
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "timeout" if !in_entry => value
+                    .parse()
+                    .map(|timeout| config.timeout = timeout)
+                    .map_err(|_| ConfigErrorKind::InvalidValue(truncated(key))),
+                "resolution" if !in_entry => parse_resolution(value)
+                    .map(|resolution| config.resolution = Some(resolution))
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "verbose" if !in_entry => parse_bool(value)
+                    .map(|verbose| config.verbose = verbose)
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "kernel_stack" if !in_entry => parse_size(value)
+                    .filter(|&size| size > 0)
+                    .map(|size| config.kernel_stack_size = size)
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "kernel_base" if !in_entry => parse_kernel_base(value)
+                    .map(|base| config.kernel_base = base)
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "kaslr" if !in_entry => parse_bool(value)
+                    .map(|kaslr| config.kaslr = kaslr)
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "signature" if !in_entry => SignaturePolicy::parse(value)
+                    .map(|policy| config.signature_policy = policy)
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "log" if !in_entry => LogFilter::parse(value)
+                    .map(|filter| config.log_filter = filter)
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "dump_memmap" if !in_entry => parse_bool(value)
+                    .map(|dump_memmap| config.dump_memmap = dump_memmap)
+                    .ok_or_else(|| ConfigErrorKind::InvalidValue(truncated(key))),
+                "default" if !in_entry => {
+                    default_title = Some((line_number, truncated(value)));
+                    Ok(())
+                }
+                _ => Err(ConfigErrorKind::UnknownKey(truncated(key))),
+            };
+            if let Err(kind) = result {
+                error(kind);
+            }
+        }
+
+        if config.entries.is_empty() {
+            config.entries.push(defaults).unwrap();
+        }
+        if let Some((line, title)) = default_title {
+            // Either the title of an entry or its index
+            let index = config
+                .find_entry(title.as_str())
+                .or_else(|| title.parse().ok())
+                .filter(|&index| index < config.entries.len());
+            match index {
+                Some(index) => config.default_entry = index,
+                None => {
+                    let _ = errors.push(ConfigError {
+                        line,
+                        kind: ConfigErrorKind::UnknownDefault(title),
+                    });
+                }
+            }
+        }
+
+        (config, errors)
+    }
+
+    pub fn find_entry(&self, title: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.title.as_str() == title)
+    }
+
+    pub fn default_entry(&self) -> &BootEntry {
+        &self.entries[self.default_entry]
+    }
+}
+
+/// Loads the config file at `path`, logging every parse error. Also
+/// returns whether there were any, so the caller can leave them on screen
+/// long enough to be read.
+///
+/// The built-in defaults are used when the file does not exist.
+pub fn load_config(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+    path: &str,
+) -> (BootConfig, bool) {
+    let file = match read_file(boot_services, root_dir, path) {
+        Ok(file) => file,
+        Err(EfiStatusCode::EfiNotFound) => {
+            warn!("{} not found, using built-in defaults", path);
+            return (BootConfig::default(), false);
+        }
+        Err(status) => {
+            error!(
+                "failed to read {}: {:?}, using built-in defaults",
+                path, status
+            );
+            return (BootConfig::default(), true);
+        }
+    };
+    let result = match core::str::from_utf8(file) {
+        Ok(text) => {
+            let (config, errors) = BootConfig::parse(text);
+            for error in &errors {
+                error!("{}", error);
+            }
+            (config, !errors.is_empty())
+        }
+        Err(_) => {
+            let error = ConfigError {
+                line: 0,
+                kind: ConfigErrorKind::NotUtf8,
+            };
+            error!("{}", error);
+            (BootConfig::default(), true)
+        }
+    };
+    boot_services.free_pool(file.as_mut_ptr());
+    result
+}
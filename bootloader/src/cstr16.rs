@@ -0,0 +1,200 @@
+use core::char::REPLACEMENT_CHARACTER;
+use core::fmt;
+use core::ops::Deref;
+use heapless::consts::U256;
+use heapless::{ArrayLength, Vec};
+
+type CHAR16 = u16;
+
+/// Why a string cannot be passed to the firmware as CHAR16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CStr16Error {
+    /// A NUL before the end, which would cut the string short
+    InteriorNul(usize),
+    /// No NUL at the end of a slice given with one
+    NotNulTerminated,
+    /// A character outside the Basic Multilingual Plane. CHAR16 strings
+    /// are UCS-2, so these cannot be encoded.
+    UnsupportedChar(char),
+    /// The string does not fit the buffer
+    TooLong,
+}
+
+/// A borrowed NUL terminated UCS-2 string, as the firmware takes and
+/// returns them.
+///
+/// Decoding never fails: unpaired surrogates, which some firmware does
+/// produce, are shown as U+FFFD.
+#[repr(transparent)]
+#[derive(PartialEq, Eq)]
+pub struct CStr16([CHAR16]);
+
+impl CStr16 {
+    /// `chars` must end with its only NUL.
+    pub fn from_u16_with_nul(chars: &[CHAR16]) -> Result<&Self, CStr16Error> {
+        match chars.iter().position(|&c| c == 0) {
+            Some(nul) if nul + 1 == chars.len() => {
+                Ok(unsafe { Self::from_u16_with_nul_unchecked(chars) })
+            }
+            Some(nul) => Err(CStr16Error::InteriorNul(nul)),
+            None => Err(CStr16Error::NotNulTerminated),
+        }
+    }
+
+    /// The string up to and including the first NUL in `chars`, which may
+    /// be followed by anything.
+    pub fn from_u16_until_nul(chars: &[CHAR16]) -> Result<&Self, CStr16Error> {
+        match chars.iter().position(|&c| c == 0) {
+            Some(nul) => Ok(unsafe { Self::from_u16_with_nul_unchecked(&chars[..nul + 1]) }),
+            None => Err(CStr16Error::NotNulTerminated),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `chars` must end with its only NUL.
+    pub const unsafe fn from_u16_with_nul_unchecked(chars: &[CHAR16]) -> &Self {
+        &*(chars as *const [CHAR16] as *const Self)
+    }
+
+    /// Borrows a string owned by the firmware, up to and including its
+    /// NUL.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a NUL terminated string that outlives `'a`.
+    pub unsafe fn from_ptr<'a>(ptr: *const CHAR16) -> &'a Self {
+        let mut len = 0;
+        while *ptr.add(len) != 0 {
+            len += 1;
+        }
+        Self::from_u16_with_nul_unchecked(core::slice::from_raw_parts(ptr, len + 1))
+    }
+
+    pub fn as_ptr(&self) -> *const CHAR16 {
+        self.0.as_ptr()
+    }
+
+    /// The characters without the NUL.
+    pub fn as_slice(&self) -> &[CHAR16] {
+        &self.0[..self.0.len() - 1]
+    }
+
+    pub fn as_slice_with_nul(&self) -> &[CHAR16] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        core::char::decode_utf16(self.as_slice().iter().copied())
+            .map(|c| c.unwrap_or(REPLACEMENT_CHARACTER))
+    }
+}
+
+impl fmt::Display for CStr16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.chars().try_for_each(|c| fmt::Write::write_char(f, c))
+    }
+}
+
+impl fmt::Debug for CStr16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Write::write_char(f, '"')?;
+        for c in self.chars() {
+            for escaped in c.escape_debug() {
+                fmt::Write::write_char(f, escaped)?;
+            }
+        }
+        fmt::Write::write_char(f, '"')
+    }
+}
+
+/// An owned NUL terminated UCS-2 string of at most `N` characters
+/// including the NUL, encoded from a `&str`.
+pub struct CString16<N: ArrayLength<CHAR16> = U256> {
+    chars: Vec<CHAR16, N>,
+}
+
+impl<N: ArrayLength<CHAR16>> TryFrom<&str> for CString16<N> {
+    type Error = CStr16Error;
+
+    fn try_from(s: &str) -> Result<Self, CStr16Error> {
+        let mut chars = Vec::new();
+        for (i, c) in s.chars().enumerate() {
+            let c = encode_char(c).map_err(|error| match error {
+                CStr16Error::InteriorNul(_) => CStr16Error::InteriorNul(i),
+                error => error,
+            })?;
+            chars.push(c).map_err(|_| CStr16Error::TooLong)?;
+        }
+        chars.push(0).map_err(|_| CStr16Error::TooLong)?;
+        Ok(Self { chars })
+    }
+}
+
+impl<N: ArrayLength<CHAR16>> Deref for CString16<N> {
+    type Target = CStr16;
+
+    fn deref(&self) -> &CStr16 {
+        unsafe { CStr16::from_u16_with_nul_unchecked(&self.chars) }
+    }
+}
+
+impl<N: ArrayLength<CHAR16>> fmt::Display for CString16<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<N: ArrayLength<CHAR16>> fmt::Debug for CString16<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+fn encode_char(c: char) -> Result<CHAR16, CStr16Error> {
+    match c as u32 {
+        0 => Err(CStr16Error::InteriorNul(0)),
+        code if code > 0xffff => Err(CStr16Error::UnsupportedChar(c)),
+        code => Ok(code as CHAR16),
+    }
+}
+
+/// Encodes `s` piece by piece into a buffer of `N` characters and passes
+/// each NUL terminated piece to `f`, so strings of any length can be
+/// output without an allocator.
+///
+/// Characters that cannot be encoded are replaced with U+FFFD; a NUL
+/// would end the output early and is dropped.
+pub fn for_each_chunk<N, E>(s: &str, mut f: impl FnMut(&CStr16) -> Result<(), E>) -> Result<(), E>
+where
+    N: ArrayLength<CHAR16>,
+{
+    let mut chars: Vec<CHAR16, N> = Vec::new();
+    for c in s.chars() {
+        let c = match encode_char(c) {
+            Ok(c) => c,
+            Err(CStr16Error::InteriorNul(_)) => continue,
+            Err(_) => REPLACEMENT_CHARACTER as CHAR16,
+        };
+        // Leave room for the NUL
+        if chars.len() + 1 == chars.capacity() {
+            chars.push(0).unwrap();
+            f(unsafe { CStr16::from_u16_with_nul_unchecked(&chars) })?;
+            chars.clear();
+        }
+        chars.push(c).unwrap();
+    }
+    if chars.is_empty() {
+        return Ok(());
+    }
+    chars.push(0).unwrap();
+    f(unsafe { CStr16::from_u16_with_nul_unchecked(&chars) })
+}
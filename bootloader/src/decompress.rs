@@ -0,0 +1,156 @@
+use crate::uefi::*;
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_DEFLATE: u8 = 8;
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+/// Upper bound for the size in the trailer, which is allocated before
+/// anything is decompressed
+const MAX_DECOMPRESSED_SIZE: usize = 512 * 1024 * 1024;
+
+// FLG bits
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+#[derive(Debug)]
+pub enum DecompressError {
+    Efi(EfiStatusCode),
+    BadHeader,
+    /// The size in the trailer is 0 or above `MAX_DECOMPRESSED_SIZE`
+    BadSize(usize),
+    Inflate(TINFLStatus),
+    /// The decompressed size differs from the one in the trailer
+    SizeMismatch,
+    CrcMismatch,
+}
+
+impl From<EfiStatusCode> for DecompressError {
+    fn from(status: EfiStatusCode) -> Self {
+        DecompressError::Efi(status)
+    }
+}
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// Decompresses the single member gzip stream `data` into a pool buffer,
+/// checking its size and CRC32 against the trailer.
+///
+/// The buffer must be freed with `free_pool`.
+pub fn gunzip(
+    boot_services: &EfiBootServices,
+    data: &[u8],
+) -> Result<&'static mut [u8], DecompressError> {
+    let deflate_start = gzip_header_size(data).ok_or(DecompressError::BadHeader)?;
+    if data.len() < deflate_start + GZIP_TRAILER_SIZE {
+        return Err(DecompressError::BadHeader);
+    }
+    let trailer = &data[data.len() - GZIP_TRAILER_SIZE..];
+    let expected_crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    // The size modulo 2^32, which is plenty for a kernel
+    let expected_size = u32::from_le_bytes(trailer[4..].try_into().unwrap()) as usize;
+    if expected_size == 0 || expected_size > MAX_DECOMPRESSED_SIZE {
+        return Err(DecompressError::BadSize(expected_size));
+    }
+
+    let buf = boot_services.allocate_pool(MemoryType::EfiLoaderData, expected_size)?;
+    if buf.is_null() {
+        return Err(DecompressError::Efi(EfiStatusCode::EfiOutOfResources));
+    }
+    let out = unsafe { core::slice::from_raw_parts_mut(buf, expected_size) };
+    let result =
+        inflate(&data[deflate_start..data.len() - GZIP_TRAILER_SIZE], out).and_then(|size| {
+            if size != expected_size {
+                Err(DecompressError::SizeMismatch)
+            } else if crc32(out) != expected_crc {
+                Err(DecompressError::CrcMismatch)
+            } else {
+                Ok(())
+            }
+        });
+    match result {
+        Ok(()) => Ok(out),
+        Err(err) => {
+            boot_services.free_pool(buf);
+            Err(err)
+        }
+    }
+}
+
+/// Size of the gzip member header at the start of `data`, including the
+/// optional fields announced in FLG.
+fn gzip_header_size(data: &[u8]) -> Option<usize> {
+    if data.len() < GZIP_HEADER_SIZE || !is_gzip(data) || data[2] != GZIP_DEFLATE {
+        return None;
+    }
+    let flags = data[3];
+    let mut offset = GZIP_HEADER_SIZE;
+    if flags & FEXTRA != 0 {
+        let len = u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().unwrap());
+        offset += 2 + len as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // NUL terminated
+            offset += data.get(offset..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        offset += 2;
+    }
+    (offset <= data.len()).then_some(offset)
+}
+
+/// Inflates the raw deflate stream `input` into `out`, returning the
+/// decompressed size.
+fn inflate(input: &[u8], out: &mut [u8]) -> Result<usize, DecompressError> {
+    let mut decompressor = DecompressorOxide::new();
+    let (status, _, written) = decompress(
+        &mut decompressor,
+        input,
+        out,
+        0,
+        TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+    );
+    match status {
+        TINFLStatus::Done => Ok(written),
+        // The output buffer is full but the stream is not over
+        TINFLStatus::HasMoreOutput => Err(DecompressError::SizeMismatch),
+        status => Err(DecompressError::Inflate(status)),
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 as used by gzip (IEEE 802.3, reflected).
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
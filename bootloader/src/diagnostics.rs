@@ -0,0 +1,424 @@
+use crate::ansi;
+use crate::guid::*;
+use crate::mem_desc;
+use crate::protocols::{EfiGraphicsOutputProtocol, EfiGraphicsPixelFormat};
+use crate::uefi::*;
+use core::arch::x86_64::__cpuid;
+use core::fmt::{self, Write};
+use heapless::consts::U32;
+use heapless::String;
+
+const MEMORY_TYPES: usize = MemoryType::EfiMaxMemoryType as usize;
+
+/// Writes what is needed to tell test machines apart in bug reports:
+/// firmware, configuration tables, GOP modes, memory, CPU and SMBIOS.
+///
+/// Shown from the boot menu; like everything printed, it also ends up in
+/// the boot log.
+pub fn write_report(system_table: &SystemTable<Boot>, out: &mut impl Write) -> fmt::Result {
+    write_firmware(system_table, out)?;
+    write_configuration_tables(system_table, out)?;
+    write_gop_modes(system_table.boot_services(), out)?;
+    write_memory_totals(system_table.boot_services(), out)?;
+    write_cpu(out)?;
+    write_smbios(system_table, out)
+}
+
+fn write_heading(out: &mut impl Write, heading: &str) -> fmt::Result {
+    write!(out, "{}{}{}\r\n", ansi::BOLD, heading, ansi::RESET)
+}
+
+fn write_firmware(system_table: &SystemTable<Boot>, out: &mut impl Write) -> fmt::Result {
+    write_heading(out, "Firmware")?;
+    write!(out, "  vendor:    {}\r\n", system_table.firmware_vendor())?;
+    // The firmware revision has no defined format
+    write!(
+        out,
+        "  revision:  {:#x}\r\n",
+        system_table.firmware_revision()
+    )?;
+    let revision = system_table.uefi_revision();
+    let (major, minor) = (revision >> 16, revision & 0xffff);
+    write!(out, "  UEFI:      {}.{}", major, minor / 10)?;
+    if minor % 10 != 0 {
+        write!(out, ".{}", minor % 10)?;
+    }
+    write!(out, "\r\n")
+}
+
+fn write_configuration_tables(
+    system_table: &SystemTable<Boot>,
+    out: &mut impl Write,
+) -> fmt::Result {
+    write_heading(out, "Configuration tables")?;
+    for table in system_table.configuration_tables() {
+        write!(
+            out,
+            "  {} {:<20} {:#x}\r\n",
+            table.vendor_guid,
+            configuration_table_name(&table.vendor_guid),
+            table.vendor_table as usize
+        )?;
+    }
+    Ok(())
+}
+
+fn configuration_table_name(guid: &EfiGuid) -> &'static str {
+    const NAMES: [(&EfiGuid, &str); 11] = [
+        (&ACPI_TABLE_GUID, "ACPI 1.0"),
+        (&EFI_ACPI_20_TABLE_GUID, "ACPI 2.0"),
+        (&SMBIOS_TABLE_GUID, "SMBIOS"),
+        (&SMBIOS3_TABLE_GUID, "SMBIOS 3"),
+        (&EFI_DXE_SERVICES_TABLE_GUID, "DXE services"),
+        (&EFI_HOB_LIST_GUID, "HOB list"),
+        (&EFI_MEMORY_TYPE_INFORMATION_GUID, "memory type info"),
+        (&EFI_DEBUG_IMAGE_INFO_TABLE_GUID, "debug image info"),
+        (&EFI_MEMORY_ATTRIBUTES_TABLE_GUID, "memory attributes"),
+        (&EFI_SYSTEM_RESOURCE_TABLE_GUID, "ESRT"),
+        (&EFI_RT_PROPERTIES_TABLE_GUID, "RT properties"),
+    ];
+    NAMES
+        .iter()
+        .find(|(known, _)| *known == guid)
+        .map_or("", |(_, name)| name)
+}
+
+fn write_gop_modes(boot_services: &EfiBootServices, out: &mut impl Write) -> fmt::Result {
+    write_heading(out, "GOP modes")?;
+    let gop = match boot_services.try_locate_protocol::<EfiGraphicsOutputProtocol>() {
+        Ok(gop) => gop,
+        Err(status) => return write!(out, "  not available: {:?}\r\n", status),
+    };
+    for mode in 0..gop.mode.max_mode {
+        let current = if mode == gop.mode.mode { '*' } else { ' ' };
+        match gop.query_mode(mode) {
+            Ok(info) => write!(
+                out,
+                "{} {:>3}: {}x{} {}\r\n",
+                current,
+                mode,
+                info.horizontal_resolution,
+                info.vertical_resolution,
+                pixel_format_name(info.pixel_format)
+            )?,
+            Err(status) => write!(out, "{} {:>3}: {:?}\r\n", current, mode, status)?,
+        }
+    }
+    Ok(())
+}
+
+fn pixel_format_name(format: EfiGraphicsPixelFormat) -> &'static str {
+    match format {
+        EfiGraphicsPixelFormat::PixelRedGreenBlueReserved8BitPerColor => "RGB",
+        EfiGraphicsPixelFormat::PixelBlueGreenRedReserved8BitPerColor => "BGR",
+        EfiGraphicsPixelFormat::PixelBitMask => "bitmask",
+        EfiGraphicsPixelFormat::PixelBltOnly => "blt only",
+        EfiGraphicsPixelFormat::PixelFormatMax => "invalid",
+    }
+}
+
+fn write_memory_totals(boot_services: &EfiBootServices, out: &mut impl Write) -> fmt::Result {
+    write_heading(out, "Memory")?;
+    // OEM, OS loader and unknown types are summed up as "other"
+    let mut pages = [0u64; MEMORY_TYPES];
+    let mut other_pages = 0;
+    let mem_desc_array = match mem_desc!(boot_services) {
+        Ok(mem_desc_array) => mem_desc_array,
+        Err(status) => return write!(out, "  not available: {:?}\r\n", status),
+    };
+    for desc in mem_desc_array.iter() {
+        match pages.get_mut(desc.type_ as usize) {
+            Some(pages) => *pages += desc.number_of_pages,
+            None => other_pages += desc.number_of_pages,
+        }
+    }
+    for (memory_type, &pages) in pages.iter().enumerate() {
+        if pages == 0 {
+            continue;
+        }
+        let mut name: String<U32> = String::new();
+        let _ = write!(
+            name,
+            "{:?}",
+            MemoryType::try_from(memory_type as u32).unwrap()
+        );
+        write_memory_total(out, name.as_str(), pages)?;
+    }
+    if other_pages != 0 {
+        write_memory_total(out, "other", other_pages)?;
+    }
+    let total = pages.iter().sum::<u64>() + other_pages;
+    write_memory_total(out, "total", total)
+}
+
+fn write_memory_total(out: &mut impl Write, name: &str, pages: u64) -> fmt::Result {
+    write!(
+        out,
+        "  {:<28} {:>10} pages {:>8} MiB\r\n",
+        name,
+        pages,
+        pages * PAGE_SIZE as u64 / (1024 * 1024)
+    )
+}
+
+/// Features worth knowing when a machine misbehaves, as (leaf, register,
+/// bit, name)
+const CPU_FEATURES: [(u32, Register, u32, &str); 28] = [
+    (1, Register::Edx, 0, "fpu"),
+    (1, Register::Edx, 4, "tsc"),
+    (1, Register::Edx, 5, "msr"),
+    (1, Register::Edx, 6, "pae"),
+    (1, Register::Edx, 9, "apic"),
+    (1, Register::Edx, 25, "sse"),
+    (1, Register::Edx, 26, "sse2"),
+    (1, Register::Edx, 28, "htt"),
+    (1, Register::Ecx, 0, "sse3"),
+    (1, Register::Ecx, 9, "ssse3"),
+    (1, Register::Ecx, 19, "sse4.1"),
+    (1, Register::Ecx, 20, "sse4.2"),
+    (1, Register::Ecx, 21, "x2apic"),
+    (1, Register::Ecx, 23, "popcnt"),
+    (1, Register::Ecx, 25, "aes"),
+    (1, Register::Ecx, 26, "xsave"),
+    (1, Register::Ecx, 28, "avx"),
+    (1, Register::Ecx, 30, "rdrand"),
+    (1, Register::Ecx, 31, "hypervisor"),
+    (7, Register::Ebx, 0, "fsgsbase"),
+    (7, Register::Ebx, 5, "avx2"),
+    (7, Register::Ebx, 7, "smep"),
+    (7, Register::Ebx, 18, "rdseed"),
+    (7, Register::Ebx, 20, "smap"),
+    (0x8000_0001, Register::Edx, 20, "nx"),
+    (0x8000_0001, Register::Edx, 26, "pdpe1gb"),
+    (0x8000_0001, Register::Edx, 27, "rdtscp"),
+    (0x8000_0001, Register::Edx, 29, "lm"),
+];
+
+#[derive(Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+fn write_cpu(out: &mut impl Write) -> fmt::Result {
+    write_heading(out, "CPU")?;
+    let leaf0 = __cpuid(0);
+    let max_leaf = leaf0.eax;
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+
+    let mut vendor = [0u8; 12];
+    vendor[..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+    vendor[8..].copy_from_slice(&leaf0.ecx.to_le_bytes());
+    write!(out, "  vendor:    {}\r\n", ascii_str(&vendor))?;
+
+    if max_extended_leaf >= 0x8000_0004 {
+        let mut brand = [0u8; 48];
+        for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+            let result = __cpuid(leaf);
+            for (j, register) in [result.eax, result.ebx, result.ecx, result.edx]
+                .iter()
+                .enumerate()
+            {
+                let offset = i * 16 + j * 4;
+                brand[offset..offset + 4].copy_from_slice(&register.to_le_bytes());
+            }
+        }
+        write!(out, "  brand:     {}\r\n", ascii_str(&brand).trim())?;
+    }
+
+    let signature = __cpuid(1).eax;
+    let base_family = signature >> 8 & 0xf;
+    let mut family = base_family;
+    let mut model = signature >> 4 & 0xf;
+    if base_family == 0xf {
+        family += signature >> 20 & 0xff;
+    }
+    if base_family == 0x6 || base_family == 0xf {
+        model |= (signature >> 16 & 0xf) << 4;
+    }
+    write!(
+        out,
+        "  family {:#x} model {:#x} stepping {}\r\n",
+        family,
+        model,
+        signature & 0xf
+    )?;
+
+    write!(out, "  features:")?;
+    for &(leaf, register, bit, name) in CPU_FEATURES.iter() {
+        let supported = if leaf >= 0x8000_0000 {
+            leaf <= max_extended_leaf
+        } else {
+            leaf <= max_leaf
+        };
+        if !supported {
+            continue;
+        }
+        let result = __cpuid(leaf);
+        let value = match register {
+            Register::Ebx => result.ebx,
+            Register::Ecx => result.ecx,
+            Register::Edx => result.edx,
+        };
+        if value & 1 << bit != 0 {
+            write!(out, " {}", name)?;
+        }
+    }
+    write!(out, "\r\n")
+}
+
+/// Up to the first NUL, or `?` for anything but printable ASCII.
+fn ascii_str(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let bytes = &bytes[..len];
+    if bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        core::str::from_utf8(bytes).unwrap()
+    } else {
+        "?"
+    }
+}
+
+// SMBIOS structure types
+const SMBIOS_BIOS_INFORMATION: u8 = 0;
+const SMBIOS_SYSTEM_INFORMATION: u8 = 1;
+const SMBIOS_BASEBOARD_INFORMATION: u8 = 2;
+const SMBIOS_END_OF_TABLE: u8 = 127;
+
+/// The structure table an SMBIOS entry point refers to.
+struct SmbiosTable {
+    version: (u8, u8),
+    table: &'static [u8],
+}
+
+impl SmbiosTable {
+    /// Prefers the 64-bit SMBIOS 3 entry point, which firmware may
+    /// provide alone.
+    fn find(system_table: &SystemTable<Boot>) -> Option<Self> {
+        let tables = system_table.configuration_tables();
+        let by_guid = |guid: &EfiGuid| {
+            tables
+                .iter()
+                .find(|table| table.vendor_guid == *guid)
+                .map(|table| table.vendor_table as *const u8)
+        };
+        unsafe {
+            if let Some(entry) = by_guid(&SMBIOS3_TABLE_GUID) {
+                // _SM3_ entry point: table maximum size at 12, address at 16
+                let entry = core::slice::from_raw_parts(entry, 24);
+                if &entry[..5] != b"_SM3_" {
+                    return None;
+                }
+                let len = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+                let address = u64::from_le_bytes(entry[16..24].try_into().unwrap());
+                return Some(Self {
+                    version: (entry[7], entry[8]),
+                    table: core::slice::from_raw_parts(address as *const u8, len as usize),
+                });
+            }
+            let entry = by_guid(&SMBIOS_TABLE_GUID)?;
+            // _SM_ entry point: table length at 22, address at 24
+            let entry = core::slice::from_raw_parts(entry, 31);
+            if &entry[..4] != b"_SM_" {
+                return None;
+            }
+            let len = u16::from_le_bytes(entry[22..24].try_into().unwrap());
+            let address = u32::from_le_bytes(entry[24..28].try_into().unwrap());
+            Some(Self {
+                version: (entry[6], entry[7]),
+                table: core::slice::from_raw_parts(address as *const u8, len as usize),
+            })
+        }
+    }
+
+    fn structures(&self) -> impl Iterator<Item = SmbiosStructure<'static>> {
+        let table = self.table;
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            let rest = table.get(offset..)?;
+            let len = *rest.get(1)? as usize;
+            if len < 4 {
+                return None;
+            }
+            let formatted = rest.get(..len)?;
+            // The string set ends with two NULs, even when it is empty
+            let strings = &rest[len..];
+            let end = strings.windows(2).position(|pair| pair == [0, 0])?;
+            offset += len + end + 2;
+            if formatted[0] == SMBIOS_END_OF_TABLE {
+                offset = table.len();
+            }
+            Some(SmbiosStructure {
+                formatted,
+                strings: &strings[..end],
+            })
+        })
+    }
+}
+
+struct SmbiosStructure<'a> {
+    formatted: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl SmbiosStructure<'_> {
+    fn structure_type(&self) -> u8 {
+        self.formatted[0]
+    }
+
+    /// The string whose number is stored at `offset`. Number 0 means
+    /// none.
+    fn string(&self, offset: usize) -> &str {
+        let number = match self.formatted.get(offset) {
+            Some(&number) if number > 0 => number as usize,
+            _ => return "",
+        };
+        self.strings
+            .split(|&b| b == 0)
+            .nth(number - 1)
+            .map_or("", ascii_str)
+    }
+}
+
+fn write_smbios(system_table: &SystemTable<Boot>, out: &mut impl Write) -> fmt::Result {
+    write_heading(out, "SMBIOS")?;
+    let smbios = match SmbiosTable::find(system_table) {
+        Some(smbios) => smbios,
+        None => return write!(out, "  not available\r\n"),
+    };
+    write!(
+        out,
+        "  version:   {}.{}\r\n",
+        smbios.version.0, smbios.version.1
+    )?;
+    for structure in smbios.structures() {
+        match structure.structure_type() {
+            SMBIOS_BIOS_INFORMATION => write!(
+                out,
+                "  BIOS:      {} {} {}\r\n",
+                structure.string(4),
+                structure.string(5),
+                structure.string(8)
+            )?,
+            SMBIOS_SYSTEM_INFORMATION => write!(
+                out,
+                "  system:    {} {} {} serial {}\r\n",
+                structure.string(4),
+                structure.string(5),
+                structure.string(6),
+                structure.string(7)
+            )?,
+            SMBIOS_BASEBOARD_INFORMATION => write!(
+                out,
+                "  baseboard: {} {} {} serial {}\r\n",
+                structure.string(4),
+                structure.string(5),
+                structure.string(6),
+                structure.string(7)
+            )?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+
+// d_tag
+pub const DT_NULL: i64 = 0;
+pub const DT_RELA: i64 = 7;
+pub const DT_RELASZ: i64 = 8;
+pub const DT_RELAENT: i64 = 9;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+// p_flags
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64Ehdr {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64Phdr {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64Dyn {
+    pub d_tag: i64,
+    pub d_val: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64Rela {
+    pub r_offset: u64,
+    pub r_info: u64,
+    pub r_addend: i64,
+}
+
+impl Elf64Rela {
+    pub fn r_type(&self) -> u32 {
+        self.r_info as u32
+    }
+}
+
+#[derive(Debug)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    UnsupportedClass,
+    UnsupportedMachine,
+    UnsupportedType(u16),
+    BadProgramHeader,
+    BadDynamicSection,
+    UnsupportedRelocation(u32),
+}
+
+/// A validated view of an ELF64 x86_64 image held in memory.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    header: Elf64Ehdr,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < core::mem::size_of::<Elf64Ehdr>() {
+            return Err(ElfError::TooShort);
+        }
+        let header = unsafe { data.as_ptr().cast::<Elf64Ehdr>().read_unaligned() };
+        if header.e_ident[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.e_ident[4] != ELFCLASS64 || header.e_ident[5] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if header.e_machine != EM_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+        if header.e_type != ET_EXEC && header.e_type != ET_DYN {
+            return Err(ElfError::UnsupportedType(header.e_type));
+        }
+        let phdrs_end = (header.e_phnum as u64)
+            .checked_mul(core::mem::size_of::<Elf64Phdr>() as u64)
+            .and_then(|size| size.checked_add(header.e_phoff))
+            .ok_or(ElfError::BadProgramHeader)?;
+        if header.e_phentsize as usize != core::mem::size_of::<Elf64Phdr>()
+            || phdrs_end > data.len() as u64
+        {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let elf = Self { data, header };
+        // The accessors below rely on these sums not overflowing
+        for phdr in elf.program_headers() {
+            let file_end = phdr
+                .p_offset
+                .checked_add(phdr.p_filesz)
+                .ok_or(ElfError::BadProgramHeader)?;
+            phdr.p_vaddr
+                .checked_add(phdr.p_memsz)
+                .ok_or(ElfError::BadProgramHeader)?;
+            if file_end > data.len() as u64 || phdr.p_filesz > phdr.p_memsz {
+                return Err(ElfError::BadProgramHeader);
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn header(&self) -> &Elf64Ehdr {
+        &self.header
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.header.e_entry
+    }
+
+    /// True for position independent executables, which may be loaded at
+    /// any address once their relocations are applied.
+    pub fn is_relocatable(&self) -> bool {
+        self.header.e_type == ET_DYN
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = Elf64Phdr> + '_ {
+        let phoff = self.header.e_phoff as usize;
+        (0..self.header.e_phnum as usize).map(move |i| unsafe {
+            self.data
+                .as_ptr()
+                .add(phoff + i * core::mem::size_of::<Elf64Phdr>())
+                .cast::<Elf64Phdr>()
+                .read_unaligned()
+        })
+    }
+
+    pub fn load_segments(&self) -> impl Iterator<Item = Elf64Phdr> + '_ {
+        self.program_headers().filter(|phdr| phdr.p_type == PT_LOAD)
+    }
+
+    /// The file contents of `phdr`, without the zero-filled tail.
+    pub fn segment_data(&self, phdr: &Elf64Phdr) -> &'a [u8] {
+        &self.data[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize]
+    }
+
+    /// Lowest and highest (exclusive) virtual address of the PT_LOAD segments.
+    pub fn load_address_range(&self) -> (u64, u64) {
+        self.load_segments()
+            .fold((u64::MAX, 0), |(start, end), phdr| {
+                (
+                    start.min(phdr.p_vaddr),
+                    end.max(phdr.p_vaddr + phdr.p_memsz),
+                )
+            })
+    }
+
+    /// The `Elf64Rela` entries referenced by the PT_DYNAMIC segment, if any.
+    pub fn relocations(&self) -> Result<impl Iterator<Item = Elf64Rela> + '_, ElfError> {
+        let mut rela = None;
+        let mut rela_size = 0;
+        let mut rela_entry_size = core::mem::size_of::<Elf64Rela>() as u64;
+        for phdr in self
+            .program_headers()
+            .filter(|phdr| phdr.p_type == PT_DYNAMIC)
+        {
+            for entry in self
+                .segment_data(&phdr)
+                .chunks_exact(core::mem::size_of::<Elf64Dyn>())
+            {
+                let entry = unsafe { entry.as_ptr().cast::<Elf64Dyn>().read_unaligned() };
+                match entry.d_tag {
+                    DT_NULL => break,
+                    DT_RELA => rela = Some(entry.d_val),
+                    DT_RELASZ => rela_size = entry.d_val,
+                    DT_RELAENT => rela_entry_size = entry.d_val,
+                    _ => {}
+                }
+            }
+        }
+
+        let (offset, count) = match rela {
+            Some(vaddr) => {
+                if rela_entry_size != core::mem::size_of::<Elf64Rela>() as u64 {
+                    return Err(ElfError::BadDynamicSection);
+                }
+                let offset = self
+                    .vaddr_to_offset(vaddr, rela_size)
+                    .ok_or(ElfError::BadDynamicSection)?;
+                (offset, (rela_size / rela_entry_size) as usize)
+            }
+            None => (0, 0),
+        };
+        Ok((0..count).map(move |i| unsafe {
+            self.data
+                .as_ptr()
+                .add(offset + i * core::mem::size_of::<Elf64Rela>())
+                .cast::<Elf64Rela>()
+                .read_unaligned()
+        }))
+    }
+
+    /// File offset of the `len` bytes at `vaddr`, which must lie in the
+    /// file-backed part of a single PT_LOAD segment.
+    fn vaddr_to_offset(&self, vaddr: u64, len: u64) -> Option<usize> {
+        self.load_segments()
+            .find(|phdr| {
+                vaddr >= phdr.p_vaddr
+                    && vaddr
+                        .checked_add(len)
+                        .is_some_and(|end| end <= phdr.p_vaddr + phdr.p_filesz)
+            })
+            .map(|phdr| (phdr.p_offset + (vaddr - phdr.p_vaddr)) as usize)
+    }
+}
@@ -6,8 +6,111 @@ pub const EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: EfiGuid = EfiGuid::new(
     0x964e5b22, 0x6459, 0x11d2, 0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b,
 );
 
+pub const EFI_LOADED_IMAGE_PROTOCOL_GUID: EfiGuid = EfiGuid::new(
+    0x5b1b31a1, 0x9562, 0x11d2, 0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b,
+);
+
+pub const EFI_SHELL_PARAMETERS_PROTOCOL_GUID: EfiGuid = EfiGuid::new(
+    0x752f3136, 0x4e16, 0x4fdc, 0xa2, 0x2a, 0xe5, 0xf4, 0x68, 0x12, 0xf4, 0xca,
+);
+
+pub const EFI_DEVICE_PATH_PROTOCOL_GUID: EfiGuid = EfiGuid::new(
+    0x09576e91, 0x6d3f, 0x11d2, 0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b,
+);
+
+pub const EFI_SERIAL_IO_PROTOCOL_GUID: EfiGuid = EfiGuid::new(
+    0xbb25cf6f, 0xf1d4, 0x11d2, 0x9a, 0x0c, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0xfd,
+);
+
+pub const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL_GUID: EfiGuid = EfiGuid::new(
+    0x387477c2, 0x69c7, 0x11d2, 0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b,
+);
+
+pub const EFI_RNG_PROTOCOL_GUID: EfiGuid = EfiGuid::new(
+    0x3152bca5, 0xeade, 0x433d, 0x86, 0x2e, 0xc0, 0x1c, 0xdc, 0x29, 0x1f, 0x44,
+);
+
+pub const EFI_RNG_ALGORITHM_SP800_90_HASH_256_GUID: EfiGuid = EfiGuid::new(
+    0xa7af67cb, 0x603b, 0x4d42, 0xba, 0x21, 0x70, 0xbf, 0xb6, 0x29, 0x3f, 0x96,
+);
+
+pub const EFI_RNG_ALGORITHM_SP800_90_HMAC_256_GUID: EfiGuid = EfiGuid::new(
+    0xc5149b43, 0xae85, 0x4f53, 0x99, 0x82, 0xb9, 0x43, 0x35, 0xd3, 0xa9, 0xe7,
+);
+
+pub const EFI_RNG_ALGORITHM_SP800_90_CTR_256_GUID: EfiGuid = EfiGuid::new(
+    0x44f0de6e, 0x4d8c, 0x4045, 0xa8, 0xc7, 0x4d, 0xd1, 0x68, 0x85, 0x6b, 0x9e,
+);
+
+pub const EFI_RNG_ALGORITHM_X9_31_3DES_GUID: EfiGuid = EfiGuid::new(
+    0x63c4785a, 0xca34, 0x4012, 0xa3, 0xc8, 0x0b, 0x6a, 0x32, 0x4f, 0x55, 0x46,
+);
+
+pub const EFI_RNG_ALGORITHM_X9_31_AES_GUID: EfiGuid = EfiGuid::new(
+    0xacd03321, 0x777e, 0x4d3d, 0xb1, 0xc8, 0x20, 0xcf, 0xd8, 0x88, 0x20, 0xc9,
+);
+
+pub const EFI_RNG_ALGORITHM_RAW: EfiGuid = EfiGuid::new(
+    0xe43176d7, 0xb6e8, 0x4827, 0xb7, 0x84, 0x7f, 0xfd, 0xc4, 0xb6, 0x85, 0x61,
+);
+
+pub const EFI_FILE_INFO_ID: EfiGuid = EfiGuid::new(
+    0x09576e92, 0x6d3f, 0x11d2, 0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b,
+);
+
+// Configuration tables
+pub const ACPI_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xeb9d2d30, 0x2d88, 0x11d3, 0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d,
+);
+
+pub const EFI_ACPI_20_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0x8868e871, 0xe4f1, 0x11d3, 0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81,
+);
+
+pub const SMBIOS_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xeb9d2d31, 0x2d88, 0x11d3, 0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d,
+);
+
+pub const SMBIOS3_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xf2fd1544, 0x9794, 0x4a2c, 0x99, 0x2e, 0xe5, 0xbb, 0xcf, 0x20, 0xe3, 0x94,
+);
+
+pub const EFI_DXE_SERVICES_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0x05ad34ba, 0x6f02, 0x4214, 0x95, 0x2e, 0x4d, 0xa0, 0x39, 0x8e, 0x2b, 0xb9,
+);
+
+pub const EFI_HOB_LIST_GUID: EfiGuid = EfiGuid::new(
+    0x7739f24c, 0x93d7, 0x11d4, 0x9a, 0x3a, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d,
+);
+
+pub const EFI_MEMORY_TYPE_INFORMATION_GUID: EfiGuid = EfiGuid::new(
+    0x4c19049f, 0x4137, 0x4dd3, 0x9c, 0x10, 0x8b, 0x97, 0xa8, 0x3f, 0xfd, 0xfa,
+);
+
+pub const EFI_DEBUG_IMAGE_INFO_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0x49152e77, 0x1ada, 0x4764, 0xb7, 0xa2, 0x7a, 0xfe, 0xfe, 0xd9, 0x5e, 0x8b,
+);
+
+pub const EFI_MEMORY_ATTRIBUTES_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xdcfa911d, 0x26eb, 0x469f, 0xa2, 0x20, 0x38, 0xb7, 0xdc, 0x46, 0x12, 0x20,
+);
+
+pub const EFI_SYSTEM_RESOURCE_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xb122a263, 0x3661, 0x4f68, 0x99, 0x29, 0x78, 0xf8, 0xb0, 0xd6, 0x21, 0x80,
+);
+
+pub const EFI_RT_PROPERTIES_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xeb66918a, 0x7eef, 0x402a, 0x84, 0x2e, 0x93, 0x1d, 0x21, 0xc3, 0x8a, 0xe9,
+);
+
+/// Vendor GUID of the variables owned by lemola_os. Keep in sync with
+/// kernel/src/efi.rs.
+pub const LEMOLA_OS_VENDOR_GUID: EfiGuid = EfiGuid::new(
+    0xa5b89285, 0x96a7, 0x487c, 0x97, 0xac, 0x26, 0x89, 0xa9, 0xff, 0xee, 0x90,
+);
+
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct EfiGuid {
     a: u32,
     b: u16,
@@ -71,3 +174,14 @@ impl EfiGuid {
         Self::new(0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0)
     }
 }
+
+/// The registry format, e.g. `8868e871-e4f1-11d3-bc22-0080c73c8881`
+impl core::fmt::Display for EfiGuid {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.a, self.b, self.c, self.d, self.e, self.f, self.g, self.h, self.i, self.j, self.k
+        )
+    }
+}
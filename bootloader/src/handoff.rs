@@ -0,0 +1,303 @@
+use crate::boot_info::{
+    FrameBufferInfo, KernelStackInfo, EFI_RUNTIME_OFFSET, KERNEL_STACK_TOP, PHYSICAL_MEMORY_OFFSET,
+};
+use crate::elf::{PF_W, PF_X};
+use crate::loader::LoadedKernel;
+use crate::paging::*;
+use crate::protocols::EfiLoadedImageProtocol;
+use crate::uefi::*;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+/// The address space the kernel is entered with.
+#[derive(Debug)]
+pub struct KernelAddressSpace {
+    /// Physical address of the PML4
+    pub pml4: EfiPhysicalAddress,
+    pub stack: KernelStackInfo,
+}
+
+/// Builds the address space the kernel is entered with:
+///
+/// - the kernel at the virtual addresses it is linked at,
+/// - a freshly allocated stack of `stack_size` bytes, rounded up to whole
+///   pages, right below `KERNEL_STACK_TOP` with an unmapped guard page
+///   beneath it,
+/// - all physical memory and the frame buffer at `PHYSICAL_MEMORY_OFFSET`,
+/// - the regions UEFI runtime services use at `EFI_RUNTIME_OFFSET`, where
+///   `SystemTable::set_virtual_address_map` moves them, with the
+///   permissions of `memory_attributes` where the firmware provides them,
+/// - an identity mapping of the loader image and the firmware's GDT and
+///   IDT, which are still in use right after CR3 is loaded.
+///
+/// The firmware stack is not mapped: `jump_to_kernel` switches away from
+/// it before anything is pushed.
+pub fn build_page_tables(
+    boot_services: &EfiBootServices,
+    image_handle: EfiHandle,
+    kernel: &LoadedKernel,
+    frame_buffer: &FrameBufferInfo,
+    stack_size: usize,
+    memory_attributes: Option<&EfiMemoryAttributesTable>,
+) -> Result<KernelAddressSpace, PagingError> {
+    let mut builder = PageTableBuilder::new(boot_services)?;
+    let no_execute = if nx_supported() { PAGE_NO_EXECUTE } else { 0 };
+
+    for page in 0..kernel.pages {
+        let virt = kernel.virt_base + (page * PAGE_SIZE) as u64;
+        if let Some(flags) = kernel_page_flags(kernel, virt, no_execute)? {
+            builder.map_page(virt, kernel.phys_base + (page * PAGE_SIZE) as u64, flags)?;
+        }
+    }
+
+    // The guard page is simply the page below, which nothing else maps
+    let stack_pages = stack_size.div_ceil(PAGE_SIZE);
+    let stack_phys = boot_services.allocate_pages(
+        EfiAllocateType::AllocateAnyPages,
+        MemoryType::EfiLoaderData,
+        stack_pages,
+    )?;
+    let stack = KernelStackInfo {
+        bottom: KERNEL_STACK_TOP - (stack_pages * PAGE_SIZE) as u64,
+        top: KERNEL_STACK_TOP,
+    };
+    builder.map_range(
+        stack.bottom,
+        stack_phys,
+        stack_pages * PAGE_SIZE,
+        PAGE_WRITABLE | no_execute,
+    )?;
+
+    // Sized from the current map; the page tables allocated below only
+    // change it after it has been read
+    let memmap_size = boot_services.memory_map_size(MEMORY_MAP_SPARE_DESCRIPTORS);
+    let memmap_buf = boot_services.allocate_pool(MemoryType::EfiLoaderData, memmap_size)?;
+    let mut phys_end = frame_buffer.base + frame_buffer.size as u64;
+    let result = boot_services
+        .get_memory_descriptor_array(memmap_buf, memmap_size)
+        .map_err(PagingError::Efi)
+        .and_then(|mem_desc_array| {
+            for desc in mem_desc_array.iter() {
+                let end = desc.physical_start + desc.number_of_pages * PAGE_SIZE as u64;
+                phys_end = phys_end.max(end);
+                if desc.is_runtime() {
+                    map_runtime_region(&mut builder, desc, memory_attributes, no_execute)?;
+                }
+            }
+            Ok(())
+        });
+    boot_services.free_pool(memmap_buf);
+    result?;
+    builder.map_huge_range(
+        PHYSICAL_MEMORY_OFFSET,
+        0,
+        phys_end,
+        PAGE_WRITABLE | no_execute,
+    )?;
+
+    let loaded_image = boot_services
+        .handle_protocol::<EfiLoadedImageProtocol>(image_handle)
+        .map_err(PagingError::Efi)?;
+    let image_base = loaded_image.image_base as u64;
+    builder.map_huge_range(
+        0,
+        image_base,
+        image_base + loaded_image.image_size,
+        PAGE_WRITABLE,
+    )?;
+    for table in [sgdt(), sidt()] {
+        builder.map_huge_range(
+            0,
+            table.base,
+            table.base + table.limit as u64 + 1,
+            PAGE_WRITABLE,
+        )?;
+    }
+
+    Ok(KernelAddressSpace {
+        pml4: builder.pml4(),
+        stack,
+    })
+}
+
+/// Page flags for the kernel page at `virt`, honouring the p_flags of every
+/// segment that overlaps it: text is R+X, rodata R and data/bss RW, neither
+/// of them executable. Pages no segment covers are left unmapped.
+///
+/// Fails for pages that would have to be writable and executable, e.g.
+/// when text and data share a page because the kernel was not linked
+/// with page aligned segments. See `runtime_page_flags` for the only
+/// W+X pages in the kernel's address space.
+fn kernel_page_flags(
+    kernel: &LoadedKernel,
+    virt: u64,
+    no_execute: u64,
+) -> Result<Option<u64>, PagingError> {
+    let mut page_flags = None;
+    let mut writable = false;
+    let mut executable = false;
+    for segment in kernel.segments.iter() {
+        if segment.virt >= virt + PAGE_SIZE as u64 || segment.virt + segment.mem_size <= virt {
+            continue;
+        }
+        let flags = page_flags.get_or_insert(no_execute);
+        if segment.flags & PF_W != 0 {
+            writable = true;
+            *flags |= PAGE_WRITABLE;
+        }
+        if segment.flags & PF_X != 0 {
+            executable = true;
+            *flags &= !PAGE_NO_EXECUTE;
+        }
+    }
+    if writable && executable {
+        return Err(PagingError::WritableAndExecutable(virt));
+    }
+    Ok(page_flags)
+}
+
+/// Maps the runtime region `desc` at `EFI_RUNTIME_OFFSET`. Runtime code
+/// gets the permissions of its entries in `memory_attributes`, so that
+/// its text is read-only and its data not executable.
+fn map_runtime_region(
+    builder: &mut PageTableBuilder,
+    desc: &EfiMemoryDescriptor,
+    memory_attributes: Option<&EfiMemoryAttributesTable>,
+    no_execute: u64,
+) -> Result<(), PagingError> {
+    let is_code = MemoryType::try_from(desc.type_) == Ok(MemoryType::EfiRuntimeServicesCode);
+    if let Some(table) = memory_attributes.filter(|_| is_code) {
+        let mut mapped = false;
+        // The entries of a region cover all of it
+        for entry in table
+            .entries()
+            .iter()
+            .filter(|entry| desc.contains(entry.physical_start))
+        {
+            builder.map_range(
+                EFI_RUNTIME_OFFSET + entry.physical_start,
+                entry.physical_start,
+                entry.number_of_pages as usize * PAGE_SIZE,
+                attribute_page_flags(entry.attribute, no_execute),
+            )?;
+            mapped = true;
+        }
+        if mapped {
+            return Ok(());
+        }
+    }
+    builder.map_range(
+        EFI_RUNTIME_OFFSET + desc.physical_start,
+        desc.physical_start,
+        desc.number_of_pages as usize * PAGE_SIZE,
+        runtime_page_flags(desc, no_execute),
+    )
+}
+
+/// Page flags for an entry of EFI_MEMORY_ATTRIBUTES_TABLE.
+fn attribute_page_flags(attribute: u64, no_execute: u64) -> u64 {
+    let mut flags = 0;
+    if attribute & EFI_MEMORY_RO == 0 {
+        flags |= PAGE_WRITABLE;
+    }
+    if attribute & EFI_MEMORY_XP != 0 {
+        flags |= no_execute;
+    }
+    flags
+}
+
+/// Without EFI_MEMORY_ATTRIBUTES_TABLE nothing tells code and data apart
+/// in runtime code regions, so they stay writable and executable. They
+/// are the one exception to W^X, which `kernel_page_flags` enforces for
+/// the kernel. MMIO regions must not be cached.
+fn runtime_page_flags(desc: &EfiMemoryDescriptor, no_execute: u64) -> u64 {
+    let memory_type = MemoryType::try_from(desc.type_);
+    let mut flags = PAGE_WRITABLE;
+    if memory_type != Ok(MemoryType::EfiRuntimeServicesCode) {
+        flags |= no_execute;
+    }
+    if matches!(
+        memory_type,
+        Ok(MemoryType::EfiMemoryMappedIO | MemoryType::EfiMemoryMappedIOPortSpace)
+    ) {
+        flags |= PAGE_CACHE_DISABLE;
+    }
+    flags
+}
+
+fn nx_supported() -> bool {
+    const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+    const CPUID_NX: u32 = 1 << 20;
+    __cpuid(0x8000_0000).eax >= CPUID_EXTENDED_FEATURES
+        && __cpuid(CPUID_EXTENDED_FEATURES).edx & CPUID_NX != 0
+}
+
+/// Turns on EFER.NXE, so that the NX bits in the page tables are honoured,
+/// and CR0.WP, so that read-only pages are read-only for the kernel too.
+unsafe fn enable_nx_and_write_protect() {
+    const IA32_EFER: u32 = 0xc000_0080;
+    const EFER_NXE: u64 = 1 << 11;
+    const CR0_WP: u64 = 1 << 16;
+    if nx_supported() {
+        let (low, high): (u32, u32);
+        asm!("rdmsr", in("ecx") IA32_EFER, out("eax") low, out("edx") high, options(nomem, nostack));
+        let efer = ((high as u64) << 32 | low as u64) | EFER_NXE;
+        asm!(
+            "wrmsr",
+            in("ecx") IA32_EFER,
+            in("eax") efer as u32,
+            in("edx") (efer >> 32) as u32,
+            options(nomem, nostack)
+        );
+    }
+    let cr0: u64;
+    asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+    asm!("mov cr0, {}", in(reg) cr0 | CR0_WP, options(nomem, nostack));
+}
+
+fn sgdt() -> DescriptorTablePointer {
+    let mut gdtr = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack)) };
+    gdtr
+}
+
+fn sidt() -> DescriptorTablePointer {
+    let mut idtr = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sidt [{}]", in(reg) &mut idtr, options(nostack)) };
+    idtr
+}
+
+/// Switches to `address_space` and its stack and calls the kernel entry
+/// point with `boot_info`, a virtual address in the new address space.
+///
+/// RSP is 16-byte aligned before the `call`, as the System V ABI requires,
+/// and RBP is cleared to terminate stack traces.
+///
+/// # Safety
+///
+/// Boot services must have been exited and `address_space` must map this
+/// code and the kernel.
+pub unsafe fn jump_to_kernel(address_space: &KernelAddressSpace, entry: u64, boot_info: u64) -> ! {
+    asm!("cli", options(nomem, nostack));
+    enable_nx_and_write_protect();
+    asm!(
+        "mov cr3, {pml4}",
+        "mov rsp, {stack_top}",
+        "xor ebp, ebp",
+        "call {entry}",
+        "2:",
+        "hlt",
+        "jmp 2b",
+        pml4 = in(reg) address_space.pml4,
+        stack_top = in(reg) address_space.stack.top & !0xf,
+        entry = in(reg) entry,
+        in("rdi") boot_info,
+        options(noreturn)
+    )
+}
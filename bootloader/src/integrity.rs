@@ -0,0 +1,113 @@
+use crate::config::BootEntry;
+use crate::protocols::EfiFileProtocol;
+use crate::uefi::*;
+use crate::uefi_utils::{path_with_suffix, read_file};
+use core::fmt;
+use sha2::{Digest, Sha256};
+
+pub const SHA256_SIZE: usize = 32;
+/// Appended to the kernel path to find a detached digest, which is the
+/// output of `sha256sum kernel.elf`.
+pub const DIGEST_FILE_SUFFIX: &str = ".sha256";
+
+pub type Sha256Digest = [u8; SHA256_SIZE];
+
+#[derive(Debug)]
+pub enum IntegrityError {
+    Efi(EfiStatusCode),
+    /// The detached digest file does not start with a SHA-256 in hex
+    BadDigestFile,
+    DigestMismatch {
+        expected: Sha256Digest,
+        actual: Sha256Digest,
+    },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::Efi(status) => write!(f, "failed to read the digest: {:?}", status),
+            IntegrityError::BadDigestFile => write!(f, "the digest file is malformed"),
+            IntegrityError::DigestMismatch { expected, actual } => write!(
+                f,
+                "the file is corrupted or incompletely copied\r\n\r\n\
+                 expected SHA-256: {}\r\n\
+                 actual SHA-256:   {}",
+                Hex(expected),
+                Hex(actual)
+            ),
+        }
+    }
+}
+
+pub fn sha256(data: &[u8]) -> Sha256Digest {
+    Sha256::digest(data).into()
+}
+
+/// Succeeds if there is no `expected` digest or `data` has it.
+pub fn check_sha256(data: &[u8], expected: Option<&Sha256Digest>) -> Result<(), IntegrityError> {
+    match expected {
+        Some(&expected) => {
+            let actual = sha256(data);
+            if actual != expected {
+                return Err(IntegrityError::DigestMismatch { expected, actual });
+            }
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Parses 64 hex digits, as written by `sha256sum`.
+pub fn parse_sha256(hex: &str) -> Option<Sha256Digest> {
+    if hex.len() != SHA256_SIZE * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut digest = [0; SHA256_SIZE];
+    for (byte, digits) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// The digest the kernel of `entry` must have: the `sha256` key of the
+/// entry or else the contents of the detached `<kernel>.sha256`.
+///
+/// Returns `None` when neither exists, in which case the kernel is booted
+/// unchecked.
+pub fn expected_sha256(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+    entry: &BootEntry,
+) -> Result<Option<Sha256Digest>, IntegrityError> {
+    if entry.sha256.is_some() {
+        return Ok(entry.sha256);
+    }
+    let path = match path_with_suffix(entry.kernel.as_str(), DIGEST_FILE_SUFFIX) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let file = match read_file(boot_services, root_dir, path.as_str()) {
+        Ok(file) => file,
+        Err(EfiStatusCode::EfiNotFound) => return Ok(None),
+        Err(status) => return Err(IntegrityError::Efi(status)),
+    };
+    let digest = core::str::from_utf8(file)
+        .ok()
+        .and_then(|text| text.split_whitespace().next())
+        .and_then(parse_sha256);
+    boot_services.free_pool(file.as_mut_ptr());
+    digest.map(Some).ok_or(IntegrityError::BadDigestFile)
+}
+
+/// Formats bytes as lowercase hex.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,31 @@
 #![no_std]
 #![feature(abi_efiapi)]
 
-// pub mod serial;
+pub mod ansi;
+pub mod args;
+pub mod boot_info;
+pub mod boot_log;
+pub mod chainload;
+pub mod config;
+pub mod cstr16;
+pub mod decompress;
+pub mod diagnostics;
+pub mod elf;
+pub mod font;
 pub mod guid;
+pub mod handoff;
+pub mod integrity;
+pub mod loader;
+pub mod logger;
+pub mod memmap;
+pub mod menu;
+pub mod nvram;
+pub mod paging;
+pub mod panic_report;
 pub mod protocols;
+pub mod rng;
+pub mod serial;
+pub mod signature;
 pub mod uefi;
 pub mod uefi_utils;
 pub mod utils;
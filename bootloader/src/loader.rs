@@ -0,0 +1,236 @@
+use crate::boot_info::RamdiskInfo;
+use crate::decompress::{gunzip, is_gzip, DecompressError};
+use crate::elf::{ElfError, ElfFile, R_X86_64_NONE, R_X86_64_RELATIVE};
+use crate::integrity::IntegrityError;
+use crate::paging::HUGE_PAGE_SIZE;
+use crate::protocols::EfiFileProtocol;
+use crate::rng::random_u64;
+use crate::signature::SignatureError;
+use crate::uefi::*;
+use crate::uefi_utils::{read_file, read_file_to_pages};
+use heapless::consts::U16;
+use heapless::Vec;
+
+/// Relocatable kernels are placed at a 2 MiB aligned address in this
+/// window, from `kernel_base` on, at `kernel_base` itself when KASLR is
+/// disabled.
+pub const KERNEL_WINDOW_START: u64 = 0xffff_ffff_8000_0000;
+pub const KERNEL_WINDOW_END: u64 = 0xffff_ffff_c000_0000;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Efi(EfiStatusCode),
+    Elf(ElfError),
+    Decompress(DecompressError),
+    /// A kernel that is not relocatable must be linked in the kernel
+    /// window, clear of the other mappings `build_page_tables` sets up
+    OutsideKernelWindow(u64),
+    /// A relocatable kernel does not fit in the kernel window
+    TooLarge(usize),
+    Integrity(IntegrityError),
+    Signature(SignatureError),
+}
+
+impl From<EfiStatusCode> for LoadError {
+    fn from(status: EfiStatusCode) -> Self {
+        LoadError::Efi(status)
+    }
+}
+
+impl From<DecompressError> for LoadError {
+    fn from(err: DecompressError) -> Self {
+        LoadError::Decompress(err)
+    }
+}
+
+impl From<IntegrityError> for LoadError {
+    fn from(err: IntegrityError) -> Self {
+        LoadError::Integrity(err)
+    }
+}
+
+impl From<SignatureError> for LoadError {
+    fn from(err: SignatureError) -> Self {
+        LoadError::Signature(err)
+    }
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+#[derive(Debug)]
+pub struct LoadedKernel {
+    pub entry: u64,
+    /// Virtual address the kernel is loaded at, page aligned
+    pub virt_base: u64,
+    /// `virt_base` minus the address the kernel is linked at
+    pub slide: u64,
+    pub phys_base: EfiPhysicalAddress,
+    pub pages: usize,
+    pub segments: Vec<KernelSegment, U16>,
+}
+
+/// A PT_LOAD segment of the kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelSegment {
+    pub virt: u64,
+    pub mem_size: u64,
+    /// `PF_R`, `PF_W` and `PF_X`
+    pub flags: u32,
+}
+
+/// Reads the kernel ELF at `path` and copies its PT_LOAD segments into
+/// physically contiguous pages, laid out as they are linked.
+///
+/// `check` is passed the file as read, i.e. before it is decompressed, and
+/// may reject it. A gzip compressed kernel, e.g. `\kernel.elf.gz`, is
+/// recognised by its magic and decompressed first.
+///
+/// A position independent kernel is relocated to a base in the kernel
+/// window at or above `kernel_base`, chosen at random if `kaslr` is set.
+/// Other kernels run at the address they are linked at, which must be in
+/// the kernel window too.
+///
+/// The kernel is not mapped yet; see `handoff::build_page_tables`.
+pub fn load_kernel(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+    path: &str,
+    kernel_base: u64,
+    kaslr: bool,
+    check: impl FnOnce(&[u8]) -> Result<(), LoadError>,
+) -> Result<LoadedKernel, LoadError> {
+    let file = read_file(boot_services, root_dir, path)?;
+    if let Err(err) = check(file) {
+        boot_services.free_pool(file.as_mut_ptr());
+        return Err(err);
+    }
+    let result = if is_gzip(file) {
+        gunzip(boot_services, file)
+            .map_err(LoadError::from)
+            .and_then(|image| {
+                let result = load_elf(boot_services, image, kernel_base, kaslr);
+                boot_services.free_pool(image.as_mut_ptr());
+                result
+            })
+    } else {
+        load_elf(boot_services, file, kernel_base, kaslr)
+    };
+    boot_services.free_pool(file.as_mut_ptr());
+    result
+}
+
+fn load_elf(
+    boot_services: &EfiBootServices,
+    file: &[u8],
+    kernel_base: u64,
+    kaslr: bool,
+) -> Result<LoadedKernel, LoadError> {
+    let elf = ElfFile::parse(file)?;
+    let (start, end) = elf.load_address_range();
+    if start >= end {
+        return Err(ElfError::BadProgramHeader.into());
+    }
+    let link_base = start & !(PAGE_SIZE as u64 - 1);
+    let pages = ((end - link_base) as usize).div_ceil(PAGE_SIZE);
+    let virt_base = if elf.is_relocatable() {
+        choose_base(boot_services, kernel_base, pages * PAGE_SIZE, kaslr)?
+    } else if start < KERNEL_WINDOW_START || end > KERNEL_WINDOW_END {
+        return Err(LoadError::OutsideKernelWindow(start));
+    } else {
+        link_base
+    };
+    let slide = virt_base.wrapping_sub(link_base);
+    let phys_base = boot_services.allocate_pages(
+        EfiAllocateType::AllocateAnyPages,
+        MemoryType::EfiLoaderData,
+        pages,
+    )?;
+
+    let mut segments = Vec::new();
+    for phdr in elf.load_segments() {
+        segments
+            .push(KernelSegment {
+                virt: phdr.p_vaddr.wrapping_add(slide),
+                mem_size: phdr.p_memsz,
+                flags: phdr.p_flags,
+            })
+            .map_err(|_| ElfError::BadProgramHeader)?;
+        let dest = unsafe {
+            core::slice::from_raw_parts_mut(
+                (phys_base + (phdr.p_vaddr - link_base)) as *mut u8,
+                phdr.p_memsz as usize,
+            )
+        };
+        let data = elf.segment_data(&phdr);
+        dest[..data.len()].copy_from_slice(data);
+        dest[data.len()..].fill(0);
+    }
+
+    if elf.is_relocatable() {
+        for rela in elf.relocations()? {
+            match rela.r_type() {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    // Also rejects offsets below the image, which wrap around
+                    let offset = rela.r_offset.wrapping_sub(link_base);
+                    if offset
+                        .checked_add(8)
+                        .filter(|end| *end <= (pages * PAGE_SIZE) as u64)
+                        .is_none()
+                    {
+                        return Err(ElfError::BadDynamicSection.into());
+                    }
+                    let value = slide.wrapping_add(rela.r_addend as u64);
+                    unsafe { ((phys_base + offset) as *mut u64).write_unaligned(value) };
+                }
+                r_type => return Err(ElfError::UnsupportedRelocation(r_type).into()),
+            }
+        }
+    }
+
+    Ok(LoadedKernel {
+        entry: elf.entry().wrapping_add(slide),
+        virt_base,
+        slide,
+        phys_base,
+        pages,
+        segments,
+    })
+}
+
+/// Picks a 2 MiB aligned base between `kernel_base` and the end of the
+/// kernel window for an image of `size` bytes.
+fn choose_base(
+    boot_services: &EfiBootServices,
+    kernel_base: u64,
+    size: usize,
+    kaslr: bool,
+) -> Result<u64, LoadError> {
+    let window = (KERNEL_WINDOW_END - kernel_base) as usize;
+    let size = size.next_multiple_of(HUGE_PAGE_SIZE);
+    if size > window {
+        return Err(LoadError::TooLarge(size));
+    }
+    let slot = if kaslr {
+        let slots = (window - size) / HUGE_PAGE_SIZE + 1;
+        random_u64(boot_services) % slots as u64
+    } else {
+        0
+    };
+    Ok(kernel_base + slot * HUGE_PAGE_SIZE as u64)
+}
+
+/// Loads the ramdisk at `path` into `LemolaRamdisk` pages, which the kernel
+/// sees as reserved in the memory map.
+pub fn load_ramdisk(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+    path: &str,
+) -> Result<RamdiskInfo, EfiStatusCode> {
+    let (base, len) = read_file_to_pages(boot_services, root_dir, path, MemoryType::LemolaRamdisk)?;
+    Ok(RamdiskInfo { base, len })
+}
@@ -0,0 +1,159 @@
+use crate::ansi;
+use crate::boot_log;
+use crate::println;
+use core::cell::RefCell;
+use heapless::consts::{U32, U8};
+use heapless::{String, Vec};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Records up to this level end up in the boot log even when the console
+/// filter hides them.
+const BOOT_LOG_LEVEL: LevelFilter = LevelFilter::Debug;
+
+/// Targets are module paths; this prefix is implied in filters and not
+/// printed.
+const CRATE_PREFIX: &str = "uefi_lemola_os::";
+
+/// Which records are printed, per module.
+///
+/// Parsed from `<level>[,<module>=<level>...]`, e.g.
+/// `info,loader=trace,uefi=warn`. Modules are matched with their
+/// submodules and the most specific match wins.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    level: LevelFilter,
+    modules: Vec<(String<U32>, LevelFilter), U8>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut filter = Self::default();
+        for directive in spec.split(',').map(str::trim) {
+            match directive.split_once('=') {
+                None => filter.level = directive.parse().ok()?,
+                Some((module, level)) => {
+                    let module = module.trim();
+                    let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+                    let mut name = String::new();
+                    name.push_str(module).ok()?;
+                    filter
+                        .modules
+                        .push((name, level.trim().parse().ok()?))
+                        .ok()?;
+                }
+            }
+        }
+        Some(filter)
+    }
+
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let target = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    /// Shows at least debug records of every module.
+    pub fn verbose(&mut self) {
+        self.level = self.level.max(LevelFilter::Debug);
+        for (_, level) in self.modules.iter_mut() {
+            *level = (*level).max(LevelFilter::Debug);
+        }
+    }
+
+    /// The most verbose level any module is logged at.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+/// Prints records through `print!`, i.e. to ConOut, serial and the boot
+/// log, or only to serial once boot services are gone.
+struct Logger {
+    /// `None` until the config is loaded, meaning `LogFilter::default()`
+    filter: RefCell<Option<LogFilter>>,
+}
+
+unsafe impl Sync for Logger {}
+
+impl Logger {
+    fn on_console(&self, metadata: &Metadata) -> bool {
+        let level = match &*self.filter.borrow() {
+            Some(filter) => filter.level_for(metadata.target()),
+            None => LevelFilter::Info,
+        };
+        metadata.level() <= level
+    }
+}
+
+static LOGGER: Logger = Logger {
+    filter: RefCell::new(None),
+};
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= BOOT_LOG_LEVEL || self.on_console(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let target = record.target();
+        let target = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        let (colour, reset) = match record.level() {
+            Level::Error => (ansi::RED, ansi::RESET),
+            Level::Warn => (ansi::YELLOW, ansi::RESET),
+            _ => ("", ""),
+        };
+        if self.on_console(record.metadata()) {
+            println!(
+                "{}[{:<5} {}] {}{}",
+                colour,
+                record.level(),
+                target,
+                record.args(),
+                reset
+            );
+        } else if record.level() <= BOOT_LOG_LEVEL {
+            boot_log::append_fmt(format_args!(
+                "{}[{:<5} {}] {}{}\r\n",
+                colour,
+                record.level(),
+                target,
+                record.args(),
+                reset
+            ));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger with the default filter. Must be called after
+/// `init_writer`.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(BOOT_LOG_LEVEL);
+    }
+}
+
+pub fn set_filter(filter: LogFilter) {
+    log::set_max_level(filter.max_level().max(BOOT_LOG_LEVEL));
+    *LOGGER.filter.borrow_mut() = Some(filter);
+}
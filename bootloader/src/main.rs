@@ -3,107 +3,293 @@
 #![feature(abi_efiapi)]
 
 use core::panic::PanicInfo;
-use uefi_lemola_os::dbg;
+use log::{debug, error, warn};
+use uefi_lemola_os::args::LoaderArgs;
+use uefi_lemola_os::boot_info::*;
+use uefi_lemola_os::boot_log::{self, BOOT_LOG_PATH};
+use uefi_lemola_os::chainload::chainload;
+use uefi_lemola_os::config::{load_config, CONFIG_PATH};
+use uefi_lemola_os::handoff::{build_page_tables, jump_to_kernel};
+use uefi_lemola_os::integrity::{check_sha256, expected_sha256};
+use uefi_lemola_os::loader::{load_kernel, load_ramdisk, LoadError};
+use uefi_lemola_os::logger;
+use uefi_lemola_os::memmap::{save_memory_map_csv, MEMMAP_DUMP_PATH};
+use uefi_lemola_os::menu::{error_screen, select_entry};
+use uefi_lemola_os::nvram::{restore_default_entry, save_last_entry, take_panic_report};
+use uefi_lemola_os::paging::PagingError;
+use uefi_lemola_os::panic_report;
 use uefi_lemola_os::protocols::*;
-use uefi_lemola_os::utils::loop_with_hlt;
+use uefi_lemola_os::rng::{fill_random, log_rng_algorithms};
+use uefi_lemola_os::signature::check_signature;
 use uefi_lemola_os::{mem_desc, println};
 use uefi_lemola_os::{uefi::*, uefi_utils::*};
 
+/// How long errors of a chainloaded application stay on screen
+const CHAINLOAD_ERROR_MICROSECONDS: usize = 3_000_000;
+/// How long the report of a panic during the last boot stays on screen
+const PANIC_REPORT_MICROSECONDS: usize = 5_000_000;
+/// How long config errors stay on screen before the menu clears it
+const CONFIG_ERROR_MICROSECONDS: usize = 5_000_000;
+
 #[no_mangle]
-pub extern "C" fn efi_main(image_handle: EfiHandle, system_table: &'static EfiSystemTable) {
-    init(system_table);
+pub extern "C" fn efi_main(image_handle: EfiHandle, system_table: SystemTable<Boot>) {
+    init(&system_table);
     println!("Hello World from macro");
 
-    let boot_services = system_table.get_boot_services();
-
-    let mem_desc_array = mem_desc!(boot_services);
+    let boot_services = system_table.boot_services();
 
-    use uefi_lemola_os::uefi::MemoryType::*;
-    let iter = mem_desc_array
-        .iter()
-        .filter(|desc| MemoryType::try_from(desc.type_).unwrap() == EfiConventionalMemory);
+    let args = LoaderArgs::from_image(boot_services, image_handle);
 
-    for desc in iter {
-        println!("{}", desc);
+    let protocol = boot_services.locate_protocol::<EfiSimpleFileSystemProtocol>();
+    let root_dir = protocol.root_dir();
+    let (mut config, config_errors) = load_config(boot_services, root_dir, CONFIG_PATH);
+    if config_errors {
+        boot_services.stall(CONFIG_ERROR_MICROSECONDS);
     }
-
-    let protocol = boot_services.locate_protocol::<EfiGraphicsOutputProtocol>();
-    // let protocol = locate_protocol!(boot_services, get_guid!(EfiGraphicsOutputProtocol));
-    println!("{:?}", protocol);
-    let base_addr = protocol.mode.frame_buffer_base;
-    let size = protocol.mode.frame_buffer_size;
-    for i in 0..size / 4 {
-        unsafe {
-            *((base_addr as *mut u8).add(i)) = 0xff;
-        }
+    args.apply_to_config(&mut config);
+    let mut log_filter = config.log_filter.clone();
+    if config.verbose {
+        log_filter.verbose();
     }
-    dbg!(base_addr);
-
-    let status = system_table.output_protocol().clear_screen();
-    println!("{:?}", status);
-    let status = system_table.output_protocol().reset(true);
-    println!("{:?}", status);
+    logger::set_filter(log_filter);
+    debug!(
+        "firmware: {} revision {:#x}",
+        system_table.firmware_vendor(),
+        system_table.firmware_revision()
+    );
+    let runtime_services = system_table.runtime_services();
+    if let Some(report) = take_panic_report(runtime_services) {
+        warn!("the loader panicked during the last boot:\r\n{}", report);
+        boot_services.stall(PANIC_REPORT_MICROSECONDS);
+    }
+    for entry in config.entries.iter_mut() {
+        args.apply_to_entry(entry);
+    }
+    let one_shot = restore_default_entry(runtime_services, &mut config);
+    let mut returned = false;
+    let selected = loop {
+        let selected = select_entry(&system_table, &mut config, returned);
+        let entry = &config.entries[selected];
+        if !one_shot {
+            let status = save_last_entry(runtime_services, entry.title.as_str());
+            if status.is_err() {
+                warn!("failed to save the last entry: {:?}", status);
+            }
+        }
+        let path = match &entry.chainload {
+            Some(path) => path,
+            None => break selected,
+        };
+        // Back to the menu once the application returns
+        let result = chainload(
+            boot_services,
+            image_handle,
+            root_dir,
+            path.as_str(),
+            entry.cmdline.as_str(),
+            config.signature_policy,
+        );
+        match result {
+            Ok(0) => {}
+            Ok(status) => {
+                match EfiStatusCode::try_from(status) {
+                    Ok(status) => warn!("{} returned {:?}", path, status),
+                    Err(_) => warn!("{} returned {:#x}", path, status),
+                }
+                boot_services.stall(CHAINLOAD_ERROR_MICROSECONDS);
+            }
+            Err(err) => {
+                error!("failed to chainload {}: {}", path, err);
+                boot_services.stall(CHAINLOAD_ERROR_MICROSECONDS);
+            }
+        }
+        returned = true;
+    };
+    let entry = &config.entries[selected];
+    println!("booting: {}", entry.title);
+    println!("kernel: {}", entry.kernel);
+    println!("cmdline: {}", entry.cmdline);
 
-    let protocol = boot_services.locate_protocol::<EfiSimpleFileSystemProtocol>();
-    let root_dir = protocol.root_dir();
-    use uefi_lemola_os::print;
-    unsafe {
-        for i in 0..15 {
-            print!(
-                "{:X}, ",
-                (root_dir as *const EfiFileProtocol)
-                    .cast::<u64>()
-                    .add(i)
-                    .as_ref()
-                    .unwrap()
-            );
+    match mem_desc!(boot_services) {
+        Ok(mem_desc_array) => {
+            for desc in mem_desc_array.iter() {
+                debug!("{}", desc);
+            }
         }
+        Err(status) => debug!("memory map: {:?}", status),
     }
-    println!();
-    let protocol = root_dir.open(
-        "\\kernel.elf",
-        OpenMode::EfiFileModeRead,
-        FileAttributes::EfiFileReadOnly,
-    );
-    unsafe {
-        for i in 0..15 {
-            print!(
-                "{:X}, ",
-                (protocol as *const EfiFileProtocol)
-                    .cast::<u64>()
-                    .add(i)
-                    .as_ref()
-                    .unwrap()
+
+    let gop = boot_services.locate_protocol::<EfiGraphicsOutputProtocol>();
+    if let Some((horizontal, vertical)) = config.resolution {
+        if let Err(status) = gop.set_resolution(horizontal, vertical) {
+            warn!(
+                "failed to set resolution {}x{}: {:?}",
+                horizontal, vertical, status
             );
         }
     }
+    debug!("{:?}", gop);
+    let frame_buffer = frame_buffer_info(gop);
+    panic_report::set_frame_buffer(frame_buffer);
+
+    let expected_sha256 = match expected_sha256(boot_services, root_dir, entry) {
+        Ok(digest) => digest,
+        Err(err) => error_screen(
+            &system_table,
+            "Kernel integrity check failed",
+            format_args!("{}: {}", entry.kernel, err),
+        ),
+    };
+    let kernel = match load_kernel(
+        boot_services,
+        root_dir,
+        entry.kernel.as_str(),
+        config.kernel_base,
+        config.kaslr,
+        |file| {
+            check_sha256(file, expected_sha256.as_ref())?;
+            check_signature(
+                boot_services,
+                root_dir,
+                entry.kernel.as_str(),
+                file,
+                config.signature_policy,
+            )?;
+            Ok(())
+        },
+    ) {
+        Ok(kernel) => kernel,
+        Err(LoadError::Integrity(err)) => error_screen(
+            &system_table,
+            "Kernel integrity check failed",
+            format_args!("{}: {}", entry.kernel, err),
+        ),
+        Err(LoadError::Signature(err)) => error_screen(
+            &system_table,
+            "Kernel signature check failed",
+            format_args!("{}: {}", entry.kernel, err),
+        ),
+        Err(err) => panic!("failed to load {}: {:?}", entry.kernel, err),
+    };
+    debug!("{:X?}", kernel);
+    let ramdisk = match &entry.ramdisk {
+        Some(path) => match load_ramdisk(boot_services, root_dir, path.as_str()) {
+            Ok(ramdisk) => {
+                let data =
+                    unsafe { core::slice::from_raw_parts(ramdisk.base as *const u8, ramdisk.len) };
+                let result = check_signature(
+                    boot_services,
+                    root_dir,
+                    path.as_str(),
+                    data,
+                    config.signature_policy,
+                );
+                if let Err(err) = result {
+                    error_screen(
+                        &system_table,
+                        "Ramdisk signature check failed",
+                        format_args!("{}: {}", path, err),
+                    );
+                }
+                ramdisk
+            }
+            Err(status) => panic!("failed to load {}: {:?}", path, status),
+        },
+        None => RamdiskInfo::default(),
+    };
+    debug!("{:X?}", ramdisk);
 
-    for i in size / 4..size / 5 {
-        unsafe {
-            *((base_addr as *mut u8).add(i)) = 0xfa;
+    let address_space = match build_page_tables(
+        boot_services,
+        image_handle,
+        &kernel,
+        &frame_buffer,
+        config.kernel_stack_size,
+        system_table.memory_attributes_table(),
+    ) {
+        Ok(address_space) => address_space,
+        Err(PagingError::WritableAndExecutable(virt)) => error_screen(
+            &system_table,
+            "Kernel violates W^X",
+            format_args!(
+                "{}: the page at {:#x} is both writable and executable",
+                entry.kernel, virt
+            ),
+        ),
+        Err(err) => panic!("failed to build page tables: {:?}", err),
+    };
+    debug!("{:X?}", address_space);
+
+    let boot_info = boot_services
+        .allocate_pool(MemoryType::EfiLoaderData, core::mem::size_of::<BootInfo>())
+        .expect("failed to allocate BootInfo")
+        .cast::<BootInfo>();
+    let boot_info = unsafe {
+        boot_info.write(BootInfo::new(frame_buffer, ramdisk, entry.cmdline.as_str()));
+        &mut *boot_info
+    };
+    boot_info.page_table = address_space.pml4;
+    boot_info.kernel_stack = address_space.stack;
+    boot_info.kernel_slide = kernel.slide;
+    boot_info.rng_seed_source = fill_random(boot_services, &mut boot_info.rng_seed);
+    log_rng_algorithms(boot_services);
+    debug!("rng seed: {:?}", boot_info.rng_seed_source);
+
+    // First, so that a failure ends up in the log
+    if config.dump_memmap {
+        if let Err(status) = save_memory_map_csv(boot_services, root_dir) {
+            warn!("failed to write {}: {:?}", MEMMAP_DUMP_PATH, status);
         }
     }
+    if let Err(status) = boot_log::save(boot_services, root_dir) {
+        warn!("failed to write {}: {:?}", BOOT_LOG_PATH, status);
+    }
 
-    let mem_desc_array = mem_desc!(boot_services);
-    let map_key = mem_desc_array.map_key();
-    // There must be no stdout between get_memorymap and exit_boot_services
-    let _status = boot_services
-        .exit_boot_services(image_handle, map_key)
-        .unwrap();
+    let (system_table, mut mem_desc_array) = system_table.exit_boot_services(image_handle);
+    // Nothing can be reported anymore; the kernel sees the 0
+    boot_info.runtime_services = system_table
+        .set_virtual_address_map(&mut mem_desc_array, EFI_RUNTIME_OFFSET)
+        .unwrap_or(0);
+    boot_info.memory_map = MemoryMapInfo {
+        buffer: mem_desc_array.as_ptr() as u64,
+        map_size: mem_desc_array.map_size(),
+        descriptor_size: mem_desc_array.descriptor_size(),
+    };
 
-    loop_with_hlt();
+    let boot_info = boot_info as *const BootInfo as u64 + PHYSICAL_MEMORY_OFFSET;
+    unsafe { jump_to_kernel(&address_space, kernel.entry, boot_info) }
+}
+
+fn frame_buffer_info(gop: &EfiGraphicsOutputProtocol) -> FrameBufferInfo {
+    let info = gop.mode.info;
+    FrameBufferInfo {
+        base: gop.mode.frame_buffer_base,
+        size: gop.mode.frame_buffer_size,
+        horizontal_resolution: info.horizontal_resolution,
+        vertical_resolution: info.vertical_resolution,
+        pixels_per_scan_line: info.pixels_per_scan_line,
+        pixel_format: match info.pixel_format {
+            EfiGraphicsPixelFormat::PixelRedGreenBlueReserved8BitPerColor => PixelFormat::Rgb,
+            EfiGraphicsPixelFormat::PixelBlueGreenRedReserved8BitPerColor => PixelFormat::Bgr,
+            EfiGraphicsPixelFormat::PixelBitMask => PixelFormat::Bitmask,
+            _ => PixelFormat::BltOnly,
+        },
+    }
 }
 
-fn init(system_table: &'static EfiSystemTable) {
-    let output_protocol = system_table.output_protocol();
-    unsafe {
-        WRITER.output_protocol.set(Some(output_protocol));
+fn init(system_table: &SystemTable<Boot>) {
+    init_writer(system_table);
+    logger::init();
+    panic_report::init(system_table);
+    let con_out = system_table.output_protocol();
+    con_out.reset(true);
+    match con_out.select_largest_mode() {
+        Ok((columns, rows)) => debug!("text mode: {}x{}", columns, rows),
+        Err(status) => warn!("failed to select a text mode: {:?}", status),
     }
-    output_protocol.reset(true);
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{:?}", info);
-    loop_with_hlt()
+    panic_report::report(info)
 }
@@ -0,0 +1,56 @@
+use crate::mem_desc;
+use crate::protocols::{EfiFileProtocol, FileAttributes, OpenMode};
+use crate::uefi::*;
+use crate::uefi_utils::{delete_file, write_all};
+use core::fmt::Write;
+use heapless::consts::U128;
+use heapless::String;
+
+pub const MEMMAP_DUMP_PATH: &str = "\\memmap";
+
+const CSV_HEADER: &str = "index,type,type_name,physical_start,pages,attribute\r\n";
+
+/// Writes the current memory map as CSV to `MEMMAP_DUMP_PATH`, one
+/// descriptor per row.
+///
+/// The map is fetched after the file is opened, so it is the map right
+/// before `exit_boot_services` up to the allocations of the write itself.
+pub fn save_memory_map_csv(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+) -> Result<(), EfiStatusCode> {
+    delete_file(root_dir, MEMMAP_DUMP_PATH)?;
+    let file = root_dir.open(
+        MEMMAP_DUMP_PATH,
+        OpenMode::EfiFiileModeCreate,
+        FileAttributes::EfiFileArchive,
+    )?;
+    let result = write_all(file, CSV_HEADER.as_bytes()).and_then(|()| {
+        for (index, desc) in mem_desc!(boot_services)?.iter().enumerate() {
+            write_all(file, csv_row(index, desc).as_bytes())?;
+        }
+        Ok(())
+    });
+    let status = file.close();
+    result?;
+    if status.is_err() {
+        return Err(status);
+    }
+    Ok(())
+}
+
+fn csv_row(index: usize, desc: &EfiMemoryDescriptor) -> String<U128> {
+    let mut row = String::new();
+    // At most ~100 characters, which always fit
+    let _ = write!(row, "{},{},", index, desc.type_);
+    let _ = match MemoryType::try_from(desc.type_) {
+        Ok(memory_type) => write!(row, "{:?}", memory_type),
+        Err(_) => write!(row, "Unknown"),
+    };
+    let _ = write!(
+        row,
+        ",{:#018x},{},{:#018x}\r\n",
+        desc.physical_start, desc.number_of_pages, desc.attribute
+    );
+    row
+}
@@ -0,0 +1,351 @@
+use crate::ansi;
+use crate::config::BootConfig;
+use crate::diagnostics;
+use crate::uefi::*;
+use crate::uefi_utils::write_console;
+use crate::{print, println};
+use core::fmt;
+use heapless::consts::U128;
+use heapless::String;
+
+// EFI_INPUT_KEY scan codes and control characters
+const SCAN_UP: u16 = 0x01;
+const SCAN_DOWN: u16 = 0x02;
+const SCAN_ESC: u16 = 0x17;
+const CHAR_BACKSPACE: u16 = 0x08;
+const CHAR_CARRIAGE_RETURN: u16 = 0x0d;
+
+const TICK_MICROSECONDS: usize = 10_000;
+const TICKS_PER_SECOND: u32 = 100;
+
+const TITLE_ROW: usize = 0;
+const ENTRIES_ROW: usize = 2;
+
+/// Text-mode boot menu on ConOut.
+///
+/// Up/Down select an entry, Enter boots it, `e` edits its command line
+/// and `i` shows the diagnostics page. Unless a key is pressed, the
+/// default entry is booted after `config.timeout` seconds.
+pub struct BootMenu<'a> {
+    system_table: &'a SystemTable<Boot>,
+    con_out: &'a EfiSimpleTextOutputProtocol,
+    con_in: &'a EfiSimpleTextInputProtocol,
+    boot_services: &'a EfiBootServices,
+    config: &'a mut BootConfig,
+    selected: usize,
+    columns: usize,
+    rows: usize,
+    countdown: bool,
+}
+
+/// Returns the index of the entry to boot, showing the menu when there
+/// is more than one entry to choose from or `wait_for_user` is set.
+/// The latter also disables the countdown, e.g. when a chainloaded
+/// application has returned.
+///
+/// A key pressed while the loader starts up forces the menu, without a
+/// countdown, even with a single entry or a timeout of 0.
+///
+/// An edited command line is written back to `config`.
+pub fn select_entry(
+    system_table: &SystemTable<Boot>,
+    config: &mut BootConfig,
+    wait_for_user: bool,
+) -> usize {
+    let skip_menu = config.entries.len() <= 1 || config.timeout == 0;
+    let wait_for_user =
+        wait_for_user || (skip_menu && system_table.input_protocol().read_key_stroke().is_some());
+    if wait_for_user {
+        let mut menu = BootMenu::new(system_table, config);
+        menu.countdown = false;
+        return menu.run();
+    }
+    if skip_menu {
+        return config.default_entry;
+    }
+    BootMenu::new(system_table, config).run()
+}
+
+/// Shows `title` and `details` white on red and reboots once a key is
+/// pressed. Used for errors that must stop the boot, as opposed to a
+/// panic, which is a bug in the loader.
+pub fn error_screen(system_table: &SystemTable<Boot>, title: &str, details: fmt::Arguments) -> ! {
+    let con_out = system_table.output_protocol();
+    let boot_services = system_table.boot_services();
+    boot_services.set_watchdog_timer(0);
+    con_out.set_attribute(EFI_WHITE | EFI_BACKGROUND_RED);
+    con_out.clear_screen();
+    println!("{}", title);
+    println!();
+    println!("{}", details);
+    println!();
+    print!("Press any key to reboot");
+    let con_in = system_table.input_protocol();
+    while con_in.read_key_stroke().is_none() {
+        boot_services.stall(TICK_MICROSECONDS);
+    }
+    system_table
+        .runtime_services()
+        .reset_system(EfiResetType::EfiResetCold)
+}
+
+impl<'a> BootMenu<'a> {
+    pub fn new(system_table: &'a SystemTable<Boot>, config: &'a mut BootConfig) -> Self {
+        let con_out = system_table.output_protocol();
+        let (columns, rows) = con_out.size().unwrap_or((80, 25));
+        Self {
+            system_table,
+            con_out,
+            con_in: system_table.input_protocol(),
+            boot_services: system_table.boot_services(),
+            selected: config.default_entry,
+            config,
+            columns,
+            rows,
+            countdown: true,
+        }
+    }
+
+    pub fn run(mut self) -> usize {
+        // The firmware would reset the machine while we wait for the user
+        self.boot_services.set_watchdog_timer(0);
+        self.con_out.enable_cursor(false);
+        self.draw();
+
+        let mut remaining_ticks = self
+            .countdown
+            .then_some(self.config.timeout.saturating_mul(TICKS_PER_SECOND));
+        loop {
+            let key = match self.con_in.read_key_stroke() {
+                Some(key) => key,
+                None => {
+                    if let Some(ticks) = remaining_ticks.as_mut() {
+                        if *ticks == 0 {
+                            break;
+                        }
+                        if *ticks % TICKS_PER_SECOND == 0 {
+                            self.draw_countdown(*ticks / TICKS_PER_SECOND);
+                        }
+                        *ticks -= 1;
+                    }
+                    self.boot_services.stall(TICK_MICROSECONDS);
+                    continue;
+                }
+            };
+            // Any key stops the countdown
+            if remaining_ticks.take().is_some() {
+                self.clear_line(self.countdown_row());
+            }
+
+            let len = self.config.entries.len();
+            match (key.scan_code, key.unicode_char) {
+                (SCAN_UP, _) => {
+                    self.selected = (self.selected + len - 1) % len;
+                    self.draw_entries();
+                }
+                (SCAN_DOWN, _) => {
+                    self.selected = (self.selected + 1) % len;
+                    self.draw_entries();
+                }
+                (_, CHAR_CARRIAGE_RETURN) => break,
+                (_, c) if c == b'e' as u16 => {
+                    if self.edit_cmdline() {
+                        break;
+                    }
+                    self.draw();
+                }
+                (_, c) if c == b'i' as u16 => {
+                    self.show_diagnostics();
+                    self.draw();
+                }
+                _ => {}
+            }
+        }
+
+        self.con_out.set_attribute(EFI_LIGHTGRAY);
+        self.con_out.clear_screen();
+        self.con_out.enable_cursor(true);
+        self.selected
+    }
+
+    /// Line editor for the command line of the selected entry.
+    ///
+    /// Returns true if the edit was accepted with Enter, which boots the
+    /// entry, and false if it was cancelled with Esc.
+    fn edit_cmdline(&mut self) -> bool {
+        let mut cmdline = self.config.entries[self.selected].cmdline.clone();
+        self.con_out.enable_cursor(true);
+        let accepted = loop {
+            self.draw_editor(cmdline.as_str());
+            let key = self.wait_for_key();
+            match (key.scan_code, key.unicode_char) {
+                (SCAN_ESC, _) => break false,
+                (_, CHAR_CARRIAGE_RETURN) => break true,
+                (_, CHAR_BACKSPACE) => {
+                    cmdline.pop();
+                }
+                (_, c) => {
+                    if let Some(c) = char::from_u32(c as u32).filter(|c| !c.is_control()) {
+                        // Full buffers simply ignore further input
+                        let _ = cmdline.push(c);
+                    }
+                }
+            }
+        };
+        self.con_out.enable_cursor(false);
+        if accepted {
+            self.config.entries[self.selected].cmdline = cmdline;
+        }
+        accepted
+    }
+
+    /// Shows `diagnostics::write_report` a screen at a time until it ends
+    /// or Esc is pressed.
+    fn show_diagnostics(&self) {
+        self.con_out.set_attribute(EFI_LIGHTGRAY);
+        self.con_out.clear_screen();
+        let mut pager = Pager {
+            menu: self,
+            row: 0,
+            column: 0,
+        };
+        // Only fails when Esc was pressed
+        if diagnostics::write_report(self.system_table, &mut pager).is_ok() {
+            self.write("\r\nPress any key to return");
+            self.wait_for_key();
+        }
+    }
+
+    fn wait_for_key(&self) -> EfiInputKey {
+        loop {
+            if let Some(key) = self.con_in.read_key_stroke() {
+                return key;
+            }
+            self.boot_services.stall(TICK_MICROSECONDS);
+        }
+    }
+
+    fn countdown_row(&self) -> usize {
+        ENTRIES_ROW + self.config.entries.len() + 3
+    }
+
+    fn draw(&self) {
+        self.con_out.set_attribute(EFI_LIGHTGRAY);
+        self.con_out.clear_screen();
+        self.con_out.set_cursor_position(0, TITLE_ROW);
+        self.write("lemola_os boot menu");
+        self.draw_entries();
+        self.con_out
+            .set_cursor_position(0, ENTRIES_ROW + self.config.entries.len() + 1);
+        self.write("Up/Down: select  Enter: boot  e: edit cmdline  i: info");
+    }
+
+    fn draw_entries(&self) {
+        for (i, entry) in self.config.entries.iter().enumerate() {
+            self.con_out.set_cursor_position(0, ENTRIES_ROW + i);
+            if i == self.selected {
+                self.con_out.set_attribute(EFI_BACKGROUND_LIGHTGRAY);
+            }
+            self.print(format_args!("  {}  ", entry.title));
+            self.con_out.set_attribute(EFI_LIGHTGRAY);
+            if i == self.config.default_entry {
+                self.write("(default)");
+            }
+        }
+    }
+
+    fn draw_countdown(&self, seconds: u32) {
+        self.clear_line(self.countdown_row());
+        self.con_out.set_cursor_position(0, self.countdown_row());
+        self.print(format_args!(
+            "Booting {} in {} s",
+            self.config.entries[self.config.default_entry].title, seconds
+        ));
+    }
+
+    fn draw_editor(&self, cmdline: &str) {
+        let row = self.countdown_row();
+        self.con_out.set_cursor_position(0, row);
+        self.print(format_args!("cmdline: {}", cmdline));
+        // Blank out what is left of a longer previous line
+        let len = "cmdline: ".len() + cmdline.chars().count();
+        self.blank(self.columns - len % self.columns - 1);
+        self.con_out
+            .set_cursor_position(len % self.columns, row + len / self.columns);
+    }
+
+    fn clear_line(&self, row: usize) {
+        self.con_out.set_cursor_position(0, row);
+        self.blank(self.columns - 1);
+    }
+
+    /// Writes straight to ConOut rather than through `print!`: the menu
+    /// is redrawn on every key and countdown tick, which would flood
+    /// serial and push the boot log out of its ring.
+    fn write(&self, s: &str) {
+        write_console(self.con_out, s);
+    }
+
+    fn print(&self, args: fmt::Arguments) {
+        let _ = fmt::Write::write_fmt(&mut Screen(self.con_out), args);
+    }
+
+    /// Writes `len` spaces, a line's worth at a time.
+    fn blank(&self, len: usize) {
+        let mut spaces: String<U128> = String::new();
+        while spaces.push(' ').is_ok() {}
+        let mut left = len;
+        while left > 0 {
+            let chunk = left.min(spaces.len());
+            self.write(&spaces[..chunk]);
+            left -= chunk;
+        }
+    }
+}
+
+/// `fmt::Write` for `BootMenu::print`.
+struct Screen<'a>(&'a EfiSimpleTextOutputProtocol);
+
+impl fmt::Write for Screen<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_console(self.0, s);
+        Ok(())
+    }
+}
+
+/// Writes to ConOut and waits for a key whenever the screen is full. Esc
+/// stops the output by failing the write.
+struct Pager<'a, 'b> {
+    menu: &'b BootMenu<'a>,
+    row: usize,
+    /// Characters since the last newline, for lines that wrap
+    column: usize,
+}
+
+impl fmt::Write for Pager<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            self.menu.write(line);
+            self.column += ansi::text(line)
+                .map(|text| text.chars().filter(|c| !c.is_control()).count())
+                .sum::<usize>();
+            if !line.ends_with('\n') {
+                continue;
+            }
+            self.row += 1 + self.column.saturating_sub(1) / self.menu.columns;
+            self.column = 0;
+            // Keep the last row for the prompt
+            if self.row + 1 < self.menu.rows {
+                continue;
+            }
+            self.menu.write("-- any key: more, Esc: back --");
+            let key = self.menu.wait_for_key();
+            self.menu.con_out.clear_screen();
+            self.row = 0;
+            if key.scan_code == SCAN_ESC {
+                return Err(fmt::Error);
+            }
+        }
+        Ok(())
+    }
+}
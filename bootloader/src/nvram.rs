@@ -0,0 +1,124 @@
+use crate::config::BootConfig;
+use crate::guid::LEMOLA_OS_VENDOR_GUID;
+use crate::uefi::*;
+use heapless::consts::{U1024, U64};
+use heapless::String;
+use log::warn;
+use utf16_literal::utf16;
+
+/// Title of the entry booted last time, used as the default entry.
+pub const LAST_ENTRY_VARIABLE: &[u16] = utf16!("LemolaLastEntry\0");
+/// Title of the entry to boot once on the next boot, without the menu.
+///
+/// It is runtime accessible so that the kernel can set it before a reboot.
+/// The loader deletes it as soon as it is read. Keep in sync with
+/// kernel/src/efi.rs.
+pub const ONE_SHOT_ENTRY_VARIABLE: &[u16] = utf16!("LemolaOneShotEntry\0");
+
+/// Report of the last loader panic as UTF-8, shown and deleted on the
+/// next boot.
+pub const PANIC_REPORT_VARIABLE: &[u16] = utf16!("LemolaPanicReport\0");
+pub const PANIC_REPORT_MAX: usize = 1024;
+
+const ATTRIBUTES: u32 =
+    EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS;
+
+/// Reads an entry title stored as UTF-8 in the variable `name`.
+fn get_entry_title(runtime_services: &EfiRuntimeServices, name: &[u16]) -> Option<String<U64>> {
+    let mut buf = [0u8; 64];
+    let (_, size) = runtime_services
+        .get_variable(name, &LEMOLA_OS_VENDOR_GUID, &mut buf)
+        .ok()?;
+    let title = core::str::from_utf8(&buf[..size]).ok()?;
+    Some(String::from(title))
+}
+
+pub fn last_entry(runtime_services: &EfiRuntimeServices) -> Option<String<U64>> {
+    get_entry_title(runtime_services, LAST_ENTRY_VARIABLE)
+}
+
+pub fn save_last_entry(runtime_services: &EfiRuntimeServices, title: &str) -> EfiStatusCode {
+    // Avoid wearing out the flash when the same entry is booted again
+    if last_entry(runtime_services).as_deref() == Some(title) {
+        return EfiStatusCode::EfiSuccess;
+    }
+    runtime_services.set_variable(
+        LAST_ENTRY_VARIABLE,
+        &LEMOLA_OS_VENDOR_GUID,
+        ATTRIBUTES,
+        title.as_bytes(),
+    )
+}
+
+/// Returns the one-shot entry and deletes the variable.
+pub fn take_one_shot_entry(runtime_services: &EfiRuntimeServices) -> Option<String<U64>> {
+    let title = get_entry_title(runtime_services, ONE_SHOT_ENTRY_VARIABLE)?;
+    runtime_services.set_variable(
+        ONE_SHOT_ENTRY_VARIABLE,
+        &LEMOLA_OS_VENDOR_GUID,
+        ATTRIBUTES,
+        &[],
+    );
+    Some(title)
+}
+
+pub fn set_one_shot_entry(runtime_services: &EfiRuntimeServices, title: &str) -> EfiStatusCode {
+    runtime_services.set_variable(
+        ONE_SHOT_ENTRY_VARIABLE,
+        &LEMOLA_OS_VENDOR_GUID,
+        ATTRIBUTES,
+        title.as_bytes(),
+    )
+}
+
+pub fn save_panic_report(runtime_services: &EfiRuntimeServices, report: &[u8]) -> EfiStatusCode {
+    runtime_services.set_variable(
+        PANIC_REPORT_VARIABLE,
+        &LEMOLA_OS_VENDOR_GUID,
+        ATTRIBUTES,
+        &report[..report.len().min(PANIC_REPORT_MAX)],
+    )
+}
+
+/// Returns the report of the last panic, if any, and deletes it.
+pub fn take_panic_report(runtime_services: &EfiRuntimeServices) -> Option<String<U1024>> {
+    let mut buf = [0u8; PANIC_REPORT_MAX];
+    let (_, size) = runtime_services
+        .get_variable(PANIC_REPORT_VARIABLE, &LEMOLA_OS_VENDOR_GUID, &mut buf)
+        .ok()?;
+    runtime_services.set_variable(
+        PANIC_REPORT_VARIABLE,
+        &LEMOLA_OS_VENDOR_GUID,
+        ATTRIBUTES,
+        &[],
+    );
+    let mut report = String::new();
+    report
+        .push_str(core::str::from_utf8(&buf[..size]).ok()?)
+        .ok()?;
+    Some(report)
+}
+
+/// Makes the saved entry the default of `config`.
+///
+/// A one-shot entry takes precedence over the last booted one and is
+/// booted without showing the menu. Returns true in that case.
+pub fn restore_default_entry(
+    runtime_services: &EfiRuntimeServices,
+    config: &mut BootConfig,
+) -> bool {
+    if let Some(title) = take_one_shot_entry(runtime_services) {
+        match config.find_entry(title.as_str()) {
+            Some(index) => {
+                config.default_entry = index;
+                config.timeout = 0;
+                return true;
+            }
+            None => warn!("one-shot entry `{}` does not exist", title),
+        }
+    }
+    if let Some(index) = last_entry(runtime_services).and_then(|title| config.find_entry(&title)) {
+        config.default_entry = index;
+    }
+    false
+}
@@ -0,0 +1,169 @@
+use crate::uefi::*;
+
+pub const PAGE_PRESENT: u64 = 1 << 0;
+pub const PAGE_WRITABLE: u64 = 1 << 1;
+pub const PAGE_CACHE_DISABLE: u64 = 1 << 4;
+pub const PAGE_HUGE: u64 = 1 << 7;
+pub const PAGE_NO_EXECUTE: u64 = 1 << 63;
+
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const ENTRIES: usize = 512;
+
+#[derive(Debug)]
+pub enum PagingError {
+    Efi(EfiStatusCode),
+    AlreadyMapped(u64),
+    Unaligned(u64),
+    /// A kernel page that segments need both writable and executable
+    WritableAndExecutable(u64),
+}
+
+impl From<EfiStatusCode> for PagingError {
+    fn from(status: EfiStatusCode) -> Self {
+        PagingError::Efi(status)
+    }
+}
+
+/// Builds 4-level page tables for the kernel while boot services are still
+/// up. Tables are allocated as `EfiLoaderData` and accessed through the
+/// firmware's identity mapping.
+pub struct PageTableBuilder<'a> {
+    boot_services: &'a EfiBootServices,
+    pml4: EfiPhysicalAddress,
+}
+
+impl<'a> PageTableBuilder<'a> {
+    pub fn new(boot_services: &'a EfiBootServices) -> Result<Self, PagingError> {
+        let pml4 = allocate_table(boot_services)?;
+        Ok(Self {
+            boot_services,
+            pml4,
+        })
+    }
+
+    /// Physical address of the PML4, to be loaded into CR3.
+    pub fn pml4(&self) -> EfiPhysicalAddress {
+        self.pml4
+    }
+
+    /// Maps `len` bytes at `virt` to `phys` with 4 KiB pages.
+    pub fn map_range(
+        &mut self,
+        virt: u64,
+        phys: EfiPhysicalAddress,
+        len: usize,
+        flags: u64,
+    ) -> Result<(), PagingError> {
+        for offset in (0..len).step_by(PAGE_SIZE) {
+            self.map_page(virt + offset as u64, phys + offset as u64, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Maps `[phys_start, phys_end)`, rounded out to 2 MiB, at `offset +
+    /// phys` with 2 MiB pages.
+    ///
+    /// Ranges may overlap as long as they agree on the physical address, so
+    /// that several identity mapped regions can share a huge page.
+    pub fn map_huge_range(
+        &mut self,
+        offset: u64,
+        phys_start: EfiPhysicalAddress,
+        phys_end: EfiPhysicalAddress,
+        flags: u64,
+    ) -> Result<(), PagingError> {
+        let huge_page_size = HUGE_PAGE_SIZE as u64;
+        let start = phys_start & !(huge_page_size - 1);
+        let end = (phys_end + huge_page_size - 1) & !(huge_page_size - 1);
+        for phys in (start..end).step_by(HUGE_PAGE_SIZE) {
+            self.map_huge_page(offset.wrapping_add(phys), phys, flags)?;
+        }
+        Ok(())
+    }
+
+    pub fn map_page(
+        &mut self,
+        virt: u64,
+        phys: EfiPhysicalAddress,
+        flags: u64,
+    ) -> Result<(), PagingError> {
+        if !virt.is_multiple_of(PAGE_SIZE as u64) || !phys.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(PagingError::Unaligned(virt));
+        }
+        let pdpt = self.next_table(self.pml4, index(virt, 3))?;
+        let pd = self.next_table(pdpt, index(virt, 2))?;
+        let pt = self.next_table(pd, index(virt, 1))?;
+        let entry = entry(pt, index(virt, 0));
+        if *entry & PAGE_PRESENT != 0 {
+            return Err(PagingError::AlreadyMapped(virt));
+        }
+        *entry = phys | flags | PAGE_PRESENT;
+        Ok(())
+    }
+
+    pub fn map_huge_page(
+        &mut self,
+        virt: u64,
+        phys: EfiPhysicalAddress,
+        flags: u64,
+    ) -> Result<(), PagingError> {
+        if !virt.is_multiple_of(HUGE_PAGE_SIZE as u64)
+            || !phys.is_multiple_of(HUGE_PAGE_SIZE as u64)
+        {
+            return Err(PagingError::Unaligned(virt));
+        }
+        let pdpt = self.next_table(self.pml4, index(virt, 3))?;
+        let pd = self.next_table(pdpt, index(virt, 2))?;
+        let entry = entry(pd, index(virt, 1));
+        let new_entry = phys | flags | PAGE_HUGE | PAGE_PRESENT;
+        if *entry & PAGE_PRESENT != 0 {
+            if *entry == new_entry {
+                return Ok(());
+            }
+            return Err(PagingError::AlreadyMapped(virt));
+        }
+        *entry = new_entry;
+        Ok(())
+    }
+
+    /// Returns the table `table[index]` points to, allocating it if needed.
+    fn next_table(
+        &mut self,
+        table: EfiPhysicalAddress,
+        index: usize,
+    ) -> Result<EfiPhysicalAddress, PagingError> {
+        let entry = entry(table, index);
+        if *entry & PAGE_PRESENT != 0 {
+            if *entry & PAGE_HUGE != 0 {
+                return Err(PagingError::AlreadyMapped(table));
+            }
+            return Ok(*entry & ADDRESS_MASK);
+        }
+        let next = allocate_table(self.boot_services)?;
+        // Permissions are restricted in the leaf entries only
+        *entry = next | PAGE_WRITABLE | PAGE_PRESENT;
+        Ok(next)
+    }
+}
+
+fn allocate_table(boot_services: &EfiBootServices) -> Result<EfiPhysicalAddress, PagingError> {
+    let table = boot_services.allocate_pages(
+        EfiAllocateType::AllocateAnyPages,
+        MemoryType::EfiLoaderData,
+        1,
+    )?;
+    unsafe { core::ptr::write_bytes(table as *mut u8, 0, PAGE_SIZE) };
+    Ok(table)
+}
+
+fn entry<'a>(table: EfiPhysicalAddress, index: usize) -> &'a mut u64 {
+    debug_assert!(index < ENTRIES);
+    unsafe { &mut *(table as *mut u64).add(index) }
+}
+
+/// Index into the page table of `level` (3: PML4 .. 0: PT) for `virt`.
+fn index(virt: u64, level: u32) -> usize {
+    ((virt >> (12 + 9 * level)) & (ENTRIES as u64 - 1)) as usize
+}
@@ -0,0 +1,205 @@
+use crate::ansi;
+use crate::boot_info::{FrameBufferInfo, PixelFormat};
+use crate::boot_log;
+use crate::font::{glyph, FONT_HEIGHT, FONT_WIDTH};
+use crate::nvram::{save_panic_report, PANIC_REPORT_MAX};
+use crate::println;
+use crate::uefi::*;
+use crate::utils::loop_with_hlt;
+use core::cell::Cell;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use heapless::consts::{U1024, U2048};
+use heapless::String;
+
+/// How many lines of the boot log a report shows
+const LOG_LINES: usize = 12;
+
+const MARGIN: usize = 32;
+const PADDING: usize = 12;
+const BACKGROUND: (u8, u8, u8) = (0xaa, 0x00, 0x00);
+const BORDER: (u8, u8, u8) = (0xff, 0xff, 0xff);
+const FOREGROUND: (u8, u8, u8) = (0xff, 0xff, 0xff);
+
+/// What the panic handler can still use, depending on how far the boot
+/// got.
+struct PanicContext {
+    frame_buffer: Cell<Option<FrameBufferInfo>>,
+    /// Cleared by `exit_boot_services`: once the kernel's address map is
+    /// set, calls through the physical addresses would fault
+    runtime_services: Cell<Option<&'static EfiRuntimeServices>>,
+    panicking: Cell<bool>,
+}
+
+unsafe impl Sync for PanicContext {}
+
+static CONTEXT: PanicContext = PanicContext {
+    frame_buffer: Cell::new(None),
+    runtime_services: Cell::new(None),
+    panicking: Cell::new(false),
+};
+
+pub fn init(system_table: &SystemTable<Boot>) {
+    CONTEXT
+        .runtime_services
+        .set(unsafe { system_table.table.runtime_services.as_ref() });
+}
+
+/// Where reports are drawn. Must be updated whenever the GOP mode changes.
+pub fn set_frame_buffer(frame_buffer: FrameBufferInfo) {
+    CONTEXT.frame_buffer.set(Some(frame_buffer));
+}
+
+pub(crate) fn exit_boot_services() {
+    CONTEXT.runtime_services.set(None);
+}
+
+/// Shows the panic message, its location and the end of the boot log on
+/// ConOut, serial and in a red box in the frame buffer, saves them for
+/// the next boot and halts.
+pub fn report(info: &PanicInfo) -> ! {
+    // A panic while reporting one would only recurse
+    if CONTEXT.panicking.replace(true) {
+        loop_with_hlt()
+    }
+    let mut tail_buf = [0u8; 2048];
+    // Colours of log records would only confuse the red box and the
+    // `warn!` showing the saved report
+    let mut tail: String<U2048> = String::new();
+    for text in ansi::text(boot_log::tail(&mut tail_buf, LOG_LINES)) {
+        let _ = tail.push_str(text);
+    }
+    let tail = tail.as_str();
+
+    let mut summary: String<U1024> = String::new();
+    let _ = match info.location() {
+        Some(location) => write!(
+            summary,
+            "panicked at {}:{}:{}:\r\n{}",
+            location.file(),
+            location.line(),
+            location.column(),
+            info.message()
+        ),
+        None => write!(summary, "panicked: {}", info.message()),
+    };
+
+    println!("{}", summary);
+    println!("last log lines:");
+    println!("{}", tail.trim_end());
+
+    if let Some(frame_buffer) = CONTEXT.frame_buffer.get() {
+        draw_report(&frame_buffer, summary.as_str(), tail);
+    }
+    if let Some(runtime_services) = CONTEXT.runtime_services.get() {
+        let mut saved = String::<U1024>::new();
+        let _ = write!(saved, "{}\r\n\r\n{}", summary, tail.trim_end());
+        let len = saved.len().min(PANIC_REPORT_MAX);
+        save_panic_report(runtime_services, &saved.as_bytes()[..len]);
+    }
+    loop_with_hlt()
+}
+
+fn draw_report(frame_buffer: &FrameBufferInfo, summary: &str, tail: &str) {
+    if !matches!(
+        frame_buffer.pixel_format,
+        PixelFormat::Rgb | PixelFormat::Bgr
+    ) {
+        return;
+    }
+    let width = frame_buffer.horizontal_resolution as usize;
+    let height = frame_buffer.vertical_resolution as usize;
+    if width < 2 * (MARGIN + PADDING) + FONT_WIDTH || height < 2 * (MARGIN + PADDING) + FONT_HEIGHT
+    {
+        return;
+    }
+    let columns = (width - 2 * (MARGIN + PADDING)) / FONT_WIDTH;
+    let rows = (height - 2 * (MARGIN + PADDING)) / FONT_HEIGHT;
+
+    let lines = Some("BOOTLOADER PANIC")
+        .into_iter()
+        .chain(Some(""))
+        .chain(summary.lines())
+        .chain(Some(""))
+        .chain(Some("last log lines:"))
+        .chain(tail.lines())
+        .map(|line| line.trim_end_matches('\r'));
+    let line_count = lines
+        .clone()
+        .map(|line| wrapped_rows(line, columns))
+        .sum::<usize>();
+    let box_height = line_count.min(rows) * FONT_HEIGHT + 2 * PADDING;
+
+    let screen = Screen { frame_buffer };
+    screen.fill(MARGIN, MARGIN, width - 2 * MARGIN, box_height, BORDER);
+    screen.fill(
+        MARGIN + 1,
+        MARGIN + 1,
+        width - 2 * MARGIN - 2,
+        box_height - 2,
+        BACKGROUND,
+    );
+    let mut row = 0;
+    for line in lines {
+        // Glyphs only exist for ASCII; anything else is drawn as `?`
+        let bytes = line.as_bytes();
+        for wrapped in 0..wrapped_rows(line, columns) {
+            if row == rows {
+                return;
+            }
+            let chunk = &bytes[wrapped * columns..bytes.len().min((wrapped + 1) * columns)];
+            for (column, &byte) in chunk.iter().enumerate() {
+                screen.draw_glyph(
+                    MARGIN + PADDING + column * FONT_WIDTH,
+                    MARGIN + PADDING + row * FONT_HEIGHT,
+                    byte as char,
+                );
+            }
+            row += 1;
+        }
+    }
+}
+
+fn wrapped_rows(line: &str, columns: usize) -> usize {
+    line.len().div_ceil(columns).max(1)
+}
+
+struct Screen<'a> {
+    frame_buffer: &'a FrameBufferInfo,
+}
+
+impl Screen<'_> {
+    fn put_pixel(&self, x: usize, y: usize, (r, g, b): (u8, u8, u8)) {
+        let (r, g, b) = (r as u32, g as u32, b as u32);
+        let value = match self.frame_buffer.pixel_format {
+            PixelFormat::Rgb => r | g << 8 | b << 16,
+            _ => b | g << 8 | r << 16,
+        };
+        let offset = (y * self.frame_buffer.pixels_per_scan_line as usize + x) * 4;
+        if offset + 4 > self.frame_buffer.size {
+            return;
+        }
+        unsafe { ((self.frame_buffer.base as usize + offset) as *mut u32).write_volatile(value) };
+    }
+
+    fn fill(&self, x: usize, y: usize, width: usize, height: usize, color: (u8, u8, u8)) {
+        for y in y..y + height {
+            for x in x..x + width {
+                self.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    fn draw_glyph(&self, x: usize, y: usize, c: char) {
+        for (dy, bits) in glyph(c).iter().enumerate() {
+            for dx in 0..FONT_WIDTH {
+                let color = if bits & (0x80 >> dx) != 0 {
+                    FOREGROUND
+                } else {
+                    BACKGROUND
+                };
+                self.put_pixel(x + dx, y + dy, color);
+            }
+        }
+    }
+}
@@ -1,8 +1,6 @@
-use crate::dyn_utf16_ptr;
+use crate::cstr16::{CStr16, CStr16Error, CString16};
 use crate::guid::*;
-use crate::println;
 use core::ffi::c_void;
-use core::mem::MaybeUninit;
 
 use crate::uefi::*;
 
@@ -18,17 +16,72 @@ impl_guid!(
     EfiSimpleFileSystemProtocol,
     EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID
 );
+impl_guid!(EfiLoadedImageProtocol, EFI_LOADED_IMAGE_PROTOCOL_GUID);
+impl_guid!(
+    EfiShellParametersProtocol,
+    EFI_SHELL_PARAMETERS_PROTOCOL_GUID
+);
+impl_guid!(EfiRngProtocol, EFI_RNG_PROTOCOL_GUID);
+impl_guid!(EfiDevicePathProtocol, EFI_DEVICE_PATH_PROTOCOL_GUID);
+impl_guid!(EfiSerialIoProtocol, EFI_SERIAL_IO_PROTOCOL_GUID);
+impl_guid!(
+    EfiSimpleTextOutputProtocol,
+    EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL_GUID
+);
 
 #[repr(C)]
 #[derive(Debug)]
 pub struct EfiGraphicsOutputProtocol<'a> {
-    pub query_mode: FnPtr,
-    pub set_mode: FnPtr,
+    pub query_mode: extern "efiapi" fn(
+        this: &EfiGraphicsOutputProtocol,
+        mode_number: u32,
+        size_of_info: &mut usize,
+        info: &mut *const EfiGraphicsOutputModeInformation,
+    ) -> EfiStatus,
+    pub set_mode:
+        extern "efiapi" fn(this: &EfiGraphicsOutputProtocol, mode_number: u32) -> EfiStatus,
     pub blt: FnPtr,
     pub mode: &'a EfiGraphicsOutputProtocolMode<'a>,
 }
 
-type EfiPhysicalAddress = u64;
+impl EfiGraphicsOutputProtocol<'_> {
+    pub fn query_mode(
+        &self,
+        mode_number: u32,
+    ) -> Result<&EfiGraphicsOutputModeInformation, EfiStatusCode> {
+        let mut size_of_info = 0;
+        let mut info = core::ptr::null();
+        let status = (self.query_mode)(self, mode_number, &mut size_of_info, &mut info);
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        unsafe { info.as_ref().ok_or(EfiStatusCode::EfiNotFound) }
+    }
+
+    pub fn set_mode(&self, mode_number: u32) -> EfiStatusCode {
+        let status = (self.set_mode)(self, mode_number);
+        status.try_into().unwrap()
+    }
+
+    /// Switches to the first mode with the given resolution.
+    pub fn set_resolution(&self, horizontal: u32, vertical: u32) -> Result<u32, EfiStatusCode> {
+        for mode_number in 0..self.mode.max_mode {
+            let info = match self.query_mode(mode_number) {
+                Ok(info) => info,
+                Err(_) => continue,
+            };
+            if info.horizontal_resolution == horizontal && info.vertical_resolution == vertical {
+                let status = self.set_mode(mode_number);
+                if status.is_err() {
+                    return Err(status);
+                }
+                return Ok(mode_number);
+            }
+        }
+        Err(EfiStatusCode::EfiUnsupported)
+    }
+}
 
 #[repr(C)]
 #[derive(Debug)]
@@ -53,7 +106,7 @@ pub struct EfiGraphicsOutputModeInformation {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EfiGraphicsPixelFormat {
     PixelRedGreenBlueReserved8BitPerColor,
     PixelBlueGreenRedReserved8BitPerColor,
@@ -76,20 +129,19 @@ pub struct EfiSimpleFileSystemProtocol {
     revision: u64,
     open_volume: extern "efiapi" fn(
         this: &EfiSimpleFileSystemProtocol,
-        root: &*const EfiFileProtocol,
+        root: &mut *const EfiFileProtocol,
     ) -> EfiStatus,
 }
 
 impl EfiSimpleFileSystemProtocol {
     pub fn root_dir(&self) -> &EfiFileProtocol {
-        let root_dir = &MaybeUninit::<EfiFileProtocol>::uninit().as_ptr();
-        let status = (self.open_volume)(self, root_dir);
+        let mut root_dir = core::ptr::null();
+        let status = (self.open_volume)(self, &mut root_dir);
         let status = EfiStatusCode::try_from(status).unwrap();
         if !status.is_success() {
-            println!("{:?}", status);
-            panic!("open_volume failed");
+            panic!("open_volume failed: {:?}", status);
         }
-        unsafe { (*root_dir).as_ref().expect("EfiFileProtocol is null") }
+        unsafe { root_dir.as_ref().expect("EfiFileProtocol is null") }
     }
 }
 
@@ -98,25 +150,33 @@ pub struct EfiFileProtocol {
     pub revision: u64,
     open: extern "efiapi" fn(
         this: &EfiFileProtocol,
-        new_handle: &*const EfiFileProtocol,
+        new_handle: &mut *const EfiFileProtocol,
         file_name: *const CHAR16,
         open_mode: u64,
         attributes: u64,
     ) -> EfiStatus,
-    close: FnPtr,
-    delete: FnPtr,
-    read: FnPtr,
-    write: FnPtr,
+    close: extern "efiapi" fn(this: &EfiFileProtocol) -> EfiStatus,
+    delete: extern "efiapi" fn(this: &EfiFileProtocol) -> EfiStatus,
+    read: extern "efiapi" fn(
+        this: &EfiFileProtocol,
+        buffer_size: &mut usize,
+        buffer: *mut c_void,
+    ) -> EfiStatus,
+    write: extern "efiapi" fn(
+        this: &EfiFileProtocol,
+        buffer_size: &mut usize,
+        buffer: *const c_void,
+    ) -> EfiStatus,
     get_position: FnPtr,
     set_position: FnPtr,
     get_info: extern "efiapi" fn(
         this: &EfiFileProtocol,
         information_type: &EfiGuid,
-        buffer_size: &usize,
-        buffer: *const c_void,
+        buffer_size: &mut usize,
+        buffer: *mut c_void,
     ) -> EfiStatus,
     set_info: FnPtr,
-    flush: FnPtr,
+    flush: extern "efiapi" fn(this: &EfiFileProtocol) -> EfiStatus,
     open_ex: FnPtr,
     read_ex: FnPtr,
     write_ex: FnPtr,
@@ -129,16 +189,84 @@ impl EfiFileProtocol {
         file_name: &str,
         open_mode: OpenMode,
         attribute: FileAttributes,
-    ) -> &EfiFileProtocol {
-        let protocol = MaybeUninit::<EfiFileProtocol>::uninit().as_ptr();
-        (self.open)(
+    ) -> Result<&EfiFileProtocol, EfiStatusCode> {
+        let file_name: CString16 =
+            CString16::try_from(file_name).map_err(|_| EfiStatusCode::EfiInvalidParameter)?;
+        let mut protocol = core::ptr::null();
+        let status = (self.open)(
             self,
-            &protocol,
-            dyn_utf16_ptr!(file_name),
+            &mut protocol,
+            file_name.as_ptr(),
             open_mode.into(),
             attribute.into(),
         );
-        unsafe { protocol.as_ref().expect("EfiFileProtocol was null") }
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        unsafe { protocol.as_ref().ok_or(EfiStatusCode::EfiNotFound) }
+    }
+
+    pub fn close(&self) -> EfiStatusCode {
+        let status = (self.close)(self);
+        status.try_into().unwrap()
+    }
+
+    /// Closes and deletes the file. Fails with `EfiWarnDeleteFailure`, in
+    /// which case the file is only closed.
+    pub fn delete(&self) -> EfiStatusCode {
+        let status = (self.delete)(self);
+        status.try_into().unwrap()
+    }
+
+    /// Writes `buf` at the current position and returns the number of
+    /// bytes written.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, EfiStatusCode> {
+        let mut size = buf.len();
+        let status = (self.write)(self, &mut size, buf.as_ptr().cast());
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(size)
+    }
+
+    pub fn flush(&self) -> EfiStatusCode {
+        let status = (self.flush)(self);
+        status.try_into().unwrap()
+    }
+
+    /// Reads up to `buf.len()` bytes and returns the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, EfiStatusCode> {
+        let mut size = buf.len();
+        let status = (self.read)(self, &mut size, buf.as_mut_ptr().cast());
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(size)
+    }
+
+    /// Reads the file's EfiFileInfo into `buf`, which also has to hold
+    /// the file name that follows it.
+    pub fn info<'a>(&self, buf: &'a mut [u64]) -> Result<&'a EfiFileInfo, EfiStatusCode> {
+        let mut size = core::mem::size_of_val(buf);
+        let status = (self.get_info)(self, &EFI_FILE_INFO_ID, &mut size, buf.as_mut_ptr().cast());
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        let info = unsafe { &*buf.as_ptr().cast::<EfiFileInfo>() };
+        // `file_name` trusts the size the firmware put in the struct
+        if info.size as usize > size || size > core::mem::size_of_val(buf) {
+            return Err(EfiStatusCode::EfiBadBufferSize);
+        }
+        Ok(info)
+    }
+
+    pub fn file_size(&self) -> Result<u64, EfiStatusCode> {
+        let mut buf = [0u64; 128];
+        Ok(self.info(&mut buf)?.file_size)
     }
 }
 
@@ -151,10 +279,12 @@ pub enum OpenMode {
 
 impl Into<u64> for OpenMode {
     fn into(self) -> u64 {
+        // Write implies read and create implies both: these are the only
+        // combinations Open accepts.
         match self {
             OpenMode::EfiFileModeRead => 0x0000000000000001,
-            OpenMode::EfiFileModeWrite => 0x0000000000000002,
-            OpenMode::EfiFiileModeCreate => 0x8000000000000000,
+            OpenMode::EfiFileModeWrite => 0x0000000000000003,
+            OpenMode::EfiFiileModeCreate => 0x8000000000000003,
         }
     }
 }
@@ -195,3 +325,270 @@ pub struct EfiFileInfo {
     attribute: u64,
     filename: CHAR16,
 }
+
+impl EfiFileInfo {
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn attribute(&self) -> u64 {
+        self.attribute
+    }
+
+    /// The name is stored right after the struct, in the buffer passed to
+    /// `EfiFileProtocol::info`, and ends within `size`.
+    pub fn file_name(&self) -> Result<&CStr16, CStr16Error> {
+        let offset = core::mem::offset_of!(EfiFileInfo, filename);
+        let len = (self.size as usize).saturating_sub(offset) / core::mem::size_of::<CHAR16>();
+        let chars = unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self)
+                    .cast::<u8>()
+                    .add(offset)
+                    .cast::<CHAR16>(),
+                len,
+            )
+        };
+        CStr16::from_u16_until_nul(chars)
+    }
+}
+
+#[repr(C)]
+pub struct EfiLoadedImageProtocol {
+    pub revision: u32,
+    pub parent_handle: EfiHandle,
+    pub system_table: *const EfiSystemTable,
+    // Source location of the image
+    pub device_handle: EfiHandle,
+    pub file_path: *const c_void,
+    reserved: *const c_void,
+    // Image's load options
+    pub load_options_size: u32,
+    pub load_options: *const c_void,
+    // Location where image was loaded
+    pub image_base: *const c_void,
+    pub image_size: u64,
+    pub image_code_type: u32,
+    pub image_data_type: u32,
+    unload: FnPtr,
+}
+
+impl EfiLoadedImageProtocol {
+    pub fn load_options(&self) -> &[CHAR16] {
+        if self.load_options.is_null() {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(
+                self.load_options.cast::<CHAR16>(),
+                self.load_options_size as usize / core::mem::size_of::<CHAR16>(),
+            )
+        }
+    }
+}
+
+// Device path node types
+pub const ACPI_DEVICE_PATH: u8 = 0x02;
+pub const ACPI_DP: u8 = 0x01;
+pub const MEDIA_DEVICE_PATH: u8 = 0x04;
+pub const MEDIA_FILEPATH_DP: u8 = 0x04;
+pub const END_DEVICE_PATH_TYPE: u8 = 0x7f;
+pub const END_ENTIRE_DEVICE_PATH_SUBTYPE: u8 = 0xff;
+
+/// Header of a device path node. A device path is a sequence of nodes of
+/// `length` bytes each, terminated by an end node.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EfiDevicePathProtocol {
+    pub type_: u8,
+    pub sub_type: u8,
+    pub length: [u8; 2],
+}
+
+impl EfiDevicePathProtocol {
+    pub fn new(type_: u8, sub_type: u8, length: u16) -> Self {
+        Self {
+            type_,
+            sub_type,
+            length: length.to_le_bytes(),
+        }
+    }
+
+    pub fn end() -> Self {
+        Self::new(
+            END_DEVICE_PATH_TYPE,
+            END_ENTIRE_DEVICE_PATH_SUBTYPE,
+            core::mem::size_of::<Self>() as u16,
+        )
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.type_ == END_DEVICE_PATH_TYPE && self.sub_type == END_ENTIRE_DEVICE_PATH_SUBTYPE
+    }
+
+    /// Size in bytes of the whole path starting at this node, without the
+    /// end node.
+    pub fn size(&self) -> usize {
+        let mut size = 0;
+        let mut node: *const Self = self;
+        unsafe {
+            while !(*node).is_end() {
+                let length = u16::from_le_bytes((*node).length) as usize;
+                size += length;
+                node = node.cast::<u8>().add(length).cast();
+            }
+        }
+        size
+    }
+
+    /// The whole path starting at this node, without the end node.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts((self as *const Self).cast(), self.size()) }
+    }
+
+    /// The nodes from this one up to the end node.
+    pub fn nodes(&self) -> impl Iterator<Item = &Self> {
+        let mut node: *const Self = self;
+        core::iter::from_fn(move || unsafe {
+            if (*node).is_end() {
+                return None;
+            }
+            let current = &*node;
+            node = node
+                .cast::<u8>()
+                .add(u16::from_le_bytes(current.length) as usize)
+                .cast();
+            Some(current)
+        })
+    }
+
+    /// The node's fields after the header.
+    pub fn data(&self) -> &[u8] {
+        let header = core::mem::size_of::<Self>();
+        let length = (u16::from_le_bytes(self.length) as usize).max(header);
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>().add(header),
+                length - header,
+            )
+        }
+    }
+}
+
+#[repr(C)]
+pub struct EfiShellParametersProtocol {
+    pub argv: *const *const CHAR16,
+    pub argc: usize,
+    pub std_in: *const c_void,
+    pub std_out: *const c_void,
+    pub std_err: *const c_void,
+}
+
+impl EfiShellParametersProtocol {
+    /// Returns `argv[index]` without its NUL terminator.
+    pub fn arg(&self, index: usize) -> Option<&CStr16> {
+        if index >= self.argc {
+            return None;
+        }
+        unsafe { Some(CStr16::from_ptr(*self.argv.add(index))) }
+    }
+}
+
+#[repr(C)]
+pub struct EfiRngProtocol {
+    pub get_info: extern "efiapi" fn(
+        this: &EfiRngProtocol,
+        rng_algorithm_list_size: &mut usize,
+        rng_algorithm_list: *mut EfiGuid,
+    ) -> EfiStatus,
+    pub get_rng: extern "efiapi" fn(
+        this: &EfiRngProtocol,
+        rng_algorithm: *const EfiGuid,
+        rng_value_length: usize,
+        rng_value: *mut u8,
+    ) -> EfiStatus,
+}
+
+impl EfiRngProtocol {
+    /// Writes the algorithms the firmware supports to `algorithms` and
+    /// returns how many there are, which may exceed `algorithms.len()`.
+    pub fn get_info(&self, algorithms: &mut [EfiGuid]) -> Result<usize, EfiStatusCode> {
+        let mut size = core::mem::size_of_val(algorithms);
+        let status = (self.get_info)(self, &mut size, algorithms.as_mut_ptr());
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() && status != EfiStatusCode::EfiBufferTooSmall {
+            return Err(status);
+        }
+        Ok(size / core::mem::size_of::<EfiGuid>())
+    }
+
+    /// Fills `buf` with random bytes from `algorithm`, or from the
+    /// firmware's default algorithm if it is `None`.
+    pub fn get_rng(&self, algorithm: Option<&EfiGuid>, buf: &mut [u8]) -> EfiStatusCode {
+        let algorithm = algorithm.map_or(core::ptr::null(), |algorithm| algorithm as *const _);
+        let status = (self.get_rng)(self, algorithm, buf.len(), buf.as_mut_ptr());
+        status.try_into().unwrap()
+    }
+}
+
+#[repr(C)]
+pub struct EfiSerialIoProtocol {
+    pub revision: u32,
+    reset: extern "efiapi" fn(this: &EfiSerialIoProtocol) -> EfiStatus,
+    set_attributes: FnPtr,
+    set_control: FnPtr,
+    get_control: FnPtr,
+    write: extern "efiapi" fn(
+        this: &EfiSerialIoProtocol,
+        buffer_size: &mut usize,
+        buffer: *const u8,
+    ) -> EfiStatus,
+    read: FnPtr,
+    mode: *const EfiSerialIoMode,
+}
+
+/// The current settings of a serial port. Zeroes stand for the device's
+/// defaults.
+#[repr(C)]
+#[derive(Debug)]
+pub struct EfiSerialIoMode {
+    pub control_mask: u32,
+    pub timeout: u32,
+    pub baud_rate: u64,
+    pub receive_fifo_depth: u32,
+    pub data_bits: u32,
+    pub parity: u32,
+    pub stop_bits: u32,
+}
+
+// EFI_PARITY_TYPE
+pub const EFI_EVEN_PARITY: u32 = 2;
+pub const EFI_ODD_PARITY: u32 = 3;
+pub const EFI_MARK_PARITY: u32 = 4;
+pub const EFI_SPACE_PARITY: u32 = 5;
+
+// EFI_STOP_BITS_TYPE
+pub const EFI_ONE_FIVE_STOP_BITS: u32 = 2;
+pub const EFI_TWO_STOP_BITS: u32 = 3;
+
+impl EfiSerialIoProtocol {
+    pub fn mode(&self) -> Option<&EfiSerialIoMode> {
+        unsafe { self.mode.as_ref() }
+    }
+
+    pub fn reset(&self) -> EfiStatusCode {
+        (self.reset)(self).try_into().unwrap()
+    }
+
+    /// Writes `buf` and returns how many bytes were written before a
+    /// timeout, if any.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, EfiStatusCode> {
+        let mut size = buf.len();
+        let status = (self.write)(self, &mut size, buf.as_ptr());
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() && status != EfiStatusCode::EfiTimeout {
+            return Err(status);
+        }
+        Ok(size)
+    }
+}
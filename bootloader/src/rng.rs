@@ -0,0 +1,153 @@
+use crate::boot_info::EntropySource;
+use crate::guid::*;
+use crate::protocols::EfiRngProtocol;
+use crate::uefi::EfiBootServices;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use log::debug;
+
+/// Intel recommends giving up on RDRAND after 10 consecutive failures
+const RDRAND_RETRIES: usize = 10;
+/// RDSEED fails whenever the entropy source is drained, so try harder
+const RDSEED_RETRIES: usize = 100;
+
+/// Fills `buf` with random bytes from EFI_RNG_PROTOCOL, falling back to
+/// RDSEED and RDRAND where the firmware does not provide it.
+///
+/// Returns where the bytes came from. `buf` is zeroed when no source is
+/// available, as a failing source may have filled part of it.
+pub fn fill_random(boot_services: &EfiBootServices, buf: &mut [u8]) -> EntropySource {
+    if let Ok(rng) = boot_services.try_locate_protocol::<EfiRngProtocol>() {
+        if !rng.get_rng(None, buf).is_err() {
+            return EntropySource::EfiRng;
+        }
+    }
+    if fill_with(buf, rdseed) {
+        return EntropySource::Rdseed;
+    }
+    if fill_with(buf, rdrand) {
+        return EntropySource::Rdrand;
+    }
+    buf.fill(0);
+    EntropySource::None
+}
+
+/// Returns 64 random bits from `fill_random` or, as a last resort, from
+/// the TSC, which is not random but at least differs between boots.
+pub fn random_u64(boot_services: &EfiBootServices) -> u64 {
+    let mut buf = [0u8; 8];
+    match fill_random(boot_services, &mut buf) {
+        EntropySource::None => rdtsc(),
+        _ => u64::from_le_bytes(buf),
+    }
+}
+
+/// Logs the algorithms EFI_RNG_PROTOCOL supports, if it is present.
+pub fn log_rng_algorithms(boot_services: &EfiBootServices) {
+    let rng = match boot_services.try_locate_protocol::<EfiRngProtocol>() {
+        Ok(rng) => rng,
+        Err(status) => {
+            debug!("EFI_RNG_PROTOCOL: {:?}", status);
+            return;
+        }
+    };
+    let mut algorithms = [EfiGuid::default(); 8];
+    match rng.get_info(&mut algorithms) {
+        Ok(count) => {
+            for algorithm in algorithms.iter().take(count) {
+                debug!(
+                    "EFI_RNG_PROTOCOL: {} {:X?}",
+                    rng_algorithm_name(algorithm),
+                    algorithm
+                );
+            }
+        }
+        Err(status) => debug!("EFI_RNG_PROTOCOL: get_info: {:?}", status),
+    }
+}
+
+/// Human readable name of an EFI_RNG_ALGORITHM.
+fn rng_algorithm_name(algorithm: &EfiGuid) -> &'static str {
+    match *algorithm {
+        EFI_RNG_ALGORITHM_SP800_90_HASH_256_GUID => "SP800-90 Hash_DRBG (SHA-256)",
+        EFI_RNG_ALGORITHM_SP800_90_HMAC_256_GUID => "SP800-90 HMAC_DRBG (SHA-256)",
+        EFI_RNG_ALGORITHM_SP800_90_CTR_256_GUID => "SP800-90 CTR_DRBG (AES-256)",
+        EFI_RNG_ALGORITHM_X9_31_3DES_GUID => "X9.31 (3DES)",
+        EFI_RNG_ALGORITHM_X9_31_AES_GUID => "X9.31 (AES)",
+        EFI_RNG_ALGORITHM_RAW => "raw",
+        _ => "unknown",
+    }
+}
+
+fn fill_with(buf: &mut [u8], source: fn() -> Option<u64>) -> bool {
+    for chunk in buf.chunks_mut(8) {
+        match source() {
+            Some(value) => chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]),
+            None => return false,
+        }
+    }
+    true
+}
+
+fn rdrand_supported() -> bool {
+    const CPUID_RDRAND: u32 = 1 << 30;
+    __cpuid(1).ecx & CPUID_RDRAND != 0
+}
+
+fn rdseed_supported() -> bool {
+    const CPUID_RDSEED: u32 = 1 << 18;
+    __cpuid(0).eax >= 7 && __cpuid(7).ebx & CPUID_RDSEED != 0
+}
+
+pub fn rdrand() -> Option<u64> {
+    if !rdrand_supported() {
+        return None;
+    }
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack)
+            )
+        };
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+pub fn rdseed() -> Option<u64> {
+    if !rdseed_supported() {
+        return None;
+    }
+    for _ in 0..RDSEED_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdseed {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack)
+            )
+        };
+        if ok != 0 {
+            return Some(value);
+        }
+        unsafe { asm!("pause", options(nomem, nostack)) };
+    }
+    None
+}
+
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack)) };
+    (high as u64) << 32 | low as u64
+}
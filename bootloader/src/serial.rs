@@ -0,0 +1,222 @@
+use crate::protocols::*;
+use crate::uefi::*;
+use core::arch::asm;
+
+const COM1: u16 = 0x3f8;
+/// EISA ID of a PC serial port (PNP0501), as ACPI device path nodes
+/// hold it
+const PNP0501: u32 = 0x0501_41d0;
+/// Baud rate at a divisor of 1
+const UART_CLOCK: u64 = 115_200;
+
+// 16550 registers, relative to the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+unsafe fn inb(port: u16) -> u8 {
+    let value;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Where `print!` mirrors its output for headless runs, e.g. with
+/// `qemu -serial stdio`.
+#[derive(Clone, Copy)]
+pub enum Serial {
+    /// The firmware's serial driver, while boot services are up. `com1`
+    /// holds the port's settings if it is COM1.
+    Efi {
+        serial_io: &'static EfiSerialIoProtocol,
+        com1: Option<LineSettings>,
+    },
+    /// A port ConOut already writes to, which is left to ConOut while boot
+    /// services are up so that every line shows up once
+    Console { com1: Option<LineSettings> },
+    /// The 16550 UART at COM1, programmed directly
+    Com1,
+}
+
+impl Serial {
+    /// Prefers the first EFI_SERIAL_IO_PROTOCOL port and falls back to
+    /// COM1 on firmware without one.
+    pub fn locate(boot_services: &'static EfiBootServices) -> Self {
+        let handle = match boot_services.locate_handle_buffer::<EfiSerialIoProtocol>() {
+            Ok(handles) => {
+                let handle = handles.first().copied();
+                boot_services.free_pool(handles.as_ptr().cast_mut());
+                handle
+            }
+            Err(_) => None,
+        };
+        let serial_io = handle.and_then(|handle| {
+            boot_services
+                .handle_protocol::<EfiSerialIoProtocol>(handle)
+                .ok()
+        });
+        let (handle, serial_io) = match (handle, serial_io) {
+            (Some(handle), Some(serial_io)) => (handle, serial_io),
+            _ => {
+                init_com1(LineSettings::DEFAULT);
+                return Serial::Com1;
+            }
+        };
+        let path = match boot_services.handle_protocol::<EfiDevicePathProtocol>(handle) {
+            Ok(path) => path,
+            Err(_) => {
+                return Serial::Efi {
+                    serial_io,
+                    com1: None,
+                }
+            }
+        };
+        let com1 = is_com1(path).then(|| {
+            serial_io
+                .mode()
+                .map_or(LineSettings::DEFAULT, LineSettings::from_mode)
+        });
+        if has_console(boot_services, path) {
+            Serial::Console { com1 }
+        } else {
+            Serial::Efi { serial_io, com1 }
+        }
+    }
+
+    /// The same port without the firmware's driver, which is gone after
+    /// `exit_boot_services`. Only COM1 can be driven directly, so output
+    /// to other ports ends here.
+    pub fn without_boot_services(self) -> Option<Self> {
+        match self {
+            Serial::Efi {
+                com1: Some(settings),
+                ..
+            }
+            | Serial::Console {
+                com1: Some(settings),
+            } => {
+                init_com1(settings);
+                Some(Serial::Com1)
+            }
+            Serial::Com1 => Some(Serial::Com1),
+            _ => None,
+        }
+    }
+
+    pub fn write_str(&self, s: &str) {
+        match self {
+            Serial::Efi { serial_io, .. } => {
+                let _ = serial_io.write(s.as_bytes());
+            }
+            Serial::Console { .. } => {}
+            Serial::Com1 => s.bytes().for_each(com1_write_byte),
+        }
+    }
+}
+
+/// How COM1 is programmed when it is driven directly.
+#[derive(Clone, Copy)]
+pub struct LineSettings {
+    divisor: u16,
+    line_control: u8,
+}
+
+impl LineSettings {
+    /// 115200 baud 8N1
+    const DEFAULT: Self = Self {
+        divisor: 1,
+        line_control: 0x03,
+    };
+
+    /// The settings the firmware's driver uses, so that the other end
+    /// keeps up after `exit_boot_services`.
+    fn from_mode(mode: &EfiSerialIoMode) -> Self {
+        let divisor = match mode.baud_rate {
+            0 => Self::DEFAULT.divisor,
+            baud => (UART_CLOCK / baud).clamp(1, u16::MAX as u64) as u16,
+        };
+        let data_bits = match mode.data_bits {
+            bits @ 5..=8 => bits as u8 - 5,
+            _ => Self::DEFAULT.line_control & 0x03,
+        };
+        let parity = match mode.parity {
+            EFI_ODD_PARITY => 0x08,
+            EFI_EVEN_PARITY => 0x18,
+            EFI_MARK_PARITY => 0x28,
+            EFI_SPACE_PARITY => 0x38,
+            _ => 0,
+        };
+        let stop_bits = match mode.stop_bits {
+            EFI_ONE_FIVE_STOP_BITS | EFI_TWO_STOP_BITS => 0x04,
+            _ => 0,
+        };
+        Self {
+            divisor,
+            line_control: data_bits | parity | stop_bits,
+        }
+    }
+}
+
+/// Whether `path` leads to the first PC serial port.
+fn is_com1(path: &EfiDevicePathProtocol) -> bool {
+    path.nodes().any(|node| {
+        let data = node.data();
+        node.type_ == ACPI_DEVICE_PATH
+            && node.sub_type == ACPI_DP
+            && data.len() >= 8
+            && u32::from_le_bytes(data[..4].try_into().unwrap()) == PNP0501
+            && u32::from_le_bytes(data[4..8].try_into().unwrap()) == 0
+    })
+}
+
+/// Whether a text console runs on the port at `path`. Firmware such as
+/// OVMF puts a terminal on its serial port and adds it to ConOut, which
+/// then already mirrors everything `print!` writes there.
+fn has_console(boot_services: &EfiBootServices, path: &EfiDevicePathProtocol) -> bool {
+    let handles = match boot_services.locate_handle_buffer::<EfiSimpleTextOutputProtocol>() {
+        Ok(handles) => handles,
+        Err(_) => return false,
+    };
+    let port = path.as_bytes();
+    let found = handles.iter().any(|&handle| {
+        boot_services
+            .handle_protocol::<EfiDevicePathProtocol>(handle)
+            .is_ok_and(|console| console.as_bytes().starts_with(port))
+    });
+    boot_services.free_pool(handles.as_ptr().cast_mut());
+    found
+}
+
+/// Programs COM1 with `settings`, without interrupts.
+fn init_com1(settings: LineSettings) {
+    let [divisor_low, divisor_high] = settings.divisor.to_le_bytes();
+    unsafe {
+        outb(COM1 + INTERRUPT_ENABLE, 0x00);
+        // DLAB, to set the divisor
+        outb(COM1 + LINE_CONTROL, 0x80);
+        outb(COM1 + DATA, divisor_low);
+        outb(COM1 + INTERRUPT_ENABLE, divisor_high);
+        outb(COM1 + LINE_CONTROL, settings.line_control);
+        // Enable and clear the FIFOs
+        outb(COM1 + FIFO_CONTROL, 0xc7);
+        // DTR, RTS and OUT2
+        outb(COM1 + MODEM_CONTROL, 0x0b);
+    }
+}
+
+fn com1_write_byte(byte: u8) {
+    unsafe {
+        while inb(COM1 + LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        outb(COM1 + DATA, byte);
+    }
+}
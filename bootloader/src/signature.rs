@@ -0,0 +1,109 @@
+use crate::protocols::EfiFileProtocol;
+use crate::uefi::*;
+use crate::uefi_utils::{path_with_suffix, read_file};
+use core::fmt;
+use ed25519_compact::{PublicKey, Signature};
+use log::warn;
+
+/// Raw Ed25519 public key kernels and ramdisks are signed with, read at
+/// build time from the file `LEMOLA_KERNEL_SIGNING_PUB` names. No key is
+/// kept in the tree; see `keys/README.md`.
+const PUBLIC_KEY: &[u8; PublicKey::BYTES] = include_bytes!(env!(
+    "LEMOLA_KERNEL_SIGNING_PUB",
+    "set LEMOLA_KERNEL_SIGNING_PUB to the raw Ed25519 public key, e.g. with `make keys`"
+));
+
+/// Appended to the path of an image to find its detached signature, the
+/// raw 64 bytes written by `openssl pkeyutl -sign -rawin`.
+pub const SIGNATURE_FILE_SUFFIX: &str = ".sig";
+
+/// Keep warnings on screen long enough to be read
+const WARNING_MICROSECONDS: usize = 3_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignaturePolicy {
+    /// Refuse to boot images without a valid signature
+    Enforce,
+    /// Boot them anyway after a warning
+    Warn,
+    /// Do not look for signatures at all
+    Off,
+}
+
+impl SignaturePolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "enforce" => Some(SignaturePolicy::Enforce),
+            "warn" => Some(SignaturePolicy::Warn),
+            "off" => Some(SignaturePolicy::Off),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Efi(EfiStatusCode),
+    Missing,
+    Malformed,
+    /// The signature does not match the image or was made with another key
+    Invalid,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Efi(status) => {
+                write!(f, "failed to read the signature: {:?}", status)
+            }
+            SignatureError::Missing => write!(f, "the image is not signed"),
+            SignatureError::Malformed => write!(f, "the signature file is malformed"),
+            SignatureError::Invalid => write!(f, "the signature is invalid"),
+        }
+    }
+}
+
+/// Checks the detached signature of the image at `path`, whose contents
+/// are `data`, as `policy` demands.
+///
+/// Only `SignaturePolicy::Enforce` returns errors; with `Warn` they are
+/// printed instead.
+pub fn check_signature(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+    path: &str,
+    data: &[u8],
+    policy: SignaturePolicy,
+) -> Result<(), SignatureError> {
+    if policy == SignaturePolicy::Off {
+        return Ok(());
+    }
+    match verify_signature(boot_services, root_dir, path, data) {
+        Err(err) if policy == SignaturePolicy::Warn => {
+            warn!("{}: {}", path, err);
+            boot_services.stall(WARNING_MICROSECONDS);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn verify_signature(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+    path: &str,
+    data: &[u8],
+) -> Result<(), SignatureError> {
+    let signature_path =
+        path_with_suffix(path, SIGNATURE_FILE_SUFFIX).ok_or(SignatureError::Missing)?;
+    let file = match read_file(boot_services, root_dir, signature_path.as_str()) {
+        Ok(file) => file,
+        Err(EfiStatusCode::EfiNotFound) => return Err(SignatureError::Missing),
+        Err(status) => return Err(SignatureError::Efi(status)),
+    };
+    let signature = Signature::from_slice(file).map_err(|_| SignatureError::Malformed);
+    boot_services.free_pool(file.as_mut_ptr());
+    PublicKey::new(*PUBLIC_KEY)
+        .verify(data, &signature?)
+        .map_err(|_| SignatureError::Invalid)
+}
@@ -1,17 +1,34 @@
 use core::ffi::c_void;
 use core::fmt::Error;
+use core::marker::PhantomData;
 
-use crate::dyn_utf16_ptr;
+use crate::cstr16::{for_each_chunk, CStr16};
 use crate::guid::*;
-use crate::println;
+use crate::panic_report;
 use crate::protocols::EfiGraphicsOutputProtocol;
+use crate::serial::Serial;
 use crate::uefi_utils::MemoryDescriptorArray;
 use crate::uefi_utils::MemoryMap;
+use crate::uefi_utils::{get_serial, set_console, set_serial};
+use heapless::consts::U128;
+use log::{debug, trace};
 
 type CHAR16 = u16;
 pub type EfiStatus = usize;
 // *void
 pub type EfiHandle = *mut c_void;
+pub type EfiPhysicalAddress = u64;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Room for descriptors added between sizing the memory map buffer and
+/// reading the map, e.g. by the allocation of the buffer itself
+pub const MEMORY_MAP_SPARE_DESCRIPTORS: usize = 8;
+/// How often `exit_boot_services` retries with a fresh map key
+const EXIT_BOOT_SERVICES_ATTEMPTS: usize = 8;
+
+/// Characters, including the NUL, handed to `OutputString` at once
+type OutputChunk = U128;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -23,39 +40,213 @@ pub struct EfiTableHeader {
     reserved: u32,
 }
 
+/// The raw system table. Boot services and the consoles are reached
+/// through `SystemTable<Boot>` only.
 #[repr(C)]
 pub struct EfiSystemTable {
     pub hdr: EfiTableHeader,
     pub firmware_vendor: *mut CHAR16,
     pub firmware_revision: u32,
-    pub console_in_handle: EfiHandle,
-    pub con_in: *mut EfiSimpleTextInputProtocol,
-    pub console_out_handle: EfiHandle,
-    pub con_out: *mut EfiSimpleTextOutputProtocol,
-    pub standerd_error_handle: EfiHandle,
-    pub std_err: *mut EfiSimpleTextOutputProtocol,
+    console_in_handle: EfiHandle,
+    pub(crate) con_in: *mut EfiSimpleTextInputProtocol,
+    console_out_handle: EfiHandle,
+    pub(crate) con_out: *mut EfiSimpleTextOutputProtocol,
+    standerd_error_handle: EfiHandle,
+    std_err: *mut EfiSimpleTextOutputProtocol,
     pub runtime_services: *mut EfiRuntimeServices,
-    pub boot_services: *mut EfiBootServices,
+    pub(crate) boot_services: *mut EfiBootServices,
     pub number_of_table_entries: usize,
     pub configuration_table: *mut EfiConfigurationTable,
 }
 
-impl EfiSystemTable {
-    pub fn get_boot_services(&self) -> &EfiBootServices {
-        unsafe { self.boot_services.as_ref().unwrap() }
+/// `SystemTable` view while boot services are available.
+pub struct Boot;
+/// `SystemTable` view after `exit_boot_services`.
+pub struct Runtime;
+
+/// The system table in the phase `View`.
+///
+/// Boot services, ConOut/ConIn and everything allocated through them are
+/// only reachable from `SystemTable<Boot>`, which `exit_boot_services`
+/// consumes, so the borrow checker rejects their use afterwards.
+#[repr(transparent)]
+pub struct SystemTable<View> {
+    pub(crate) table: &'static EfiSystemTable,
+    _view: PhantomData<View>,
+}
+
+impl<View> SystemTable<View> {
+    pub fn runtime_services(&self) -> &EfiRuntimeServices {
+        unsafe { self.table.runtime_services.as_ref().unwrap() }
+    }
+
+    pub fn firmware_revision(&self) -> u32 {
+        self.table.firmware_revision
+    }
+
+    pub fn firmware_vendor(&self) -> &CStr16 {
+        unsafe { CStr16::from_ptr(self.table.firmware_vendor) }
+    }
+
+    /// The UEFI specification revision the firmware conforms to, e.g.
+    /// `0x0002_0046` for 2.7.
+    pub fn uefi_revision(&self) -> u32 {
+        self.table.hdr.revision
+    }
+
+    pub fn configuration_tables(&self) -> &[EfiConfigurationTable] {
+        let tables = self.table.configuration_table;
+        if tables.is_null() {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(tables, self.table.number_of_table_entries) }
+    }
+
+    pub fn memory_attributes_table(&self) -> Option<&EfiMemoryAttributesTable> {
+        self.configuration_tables()
+            .iter()
+            .find(|table| table.vendor_guid == EFI_MEMORY_ATTRIBUTES_TABLE_GUID)
+            .and_then(|table| unsafe {
+                table
+                    .vendor_table
+                    .cast::<EfiMemoryAttributesTable>()
+                    .as_ref()
+            })
+    }
+}
+
+impl SystemTable<Runtime> {
+    /// Moves every `EFI_MEMORY_RUNTIME` region of `memory_map` to its
+    /// physical address plus `offset` and switches the runtime services to
+    /// virtual addressing.
+    ///
+    /// Consumes the table: from here on runtime services only work through
+    /// the new addresses, i.e. under page tables that map the regions
+    /// there. Returns the virtual address of the runtime services table.
+    pub fn set_virtual_address_map(
+        self,
+        memory_map: &mut MemoryDescriptorArray,
+        offset: u64,
+    ) -> Result<u64, EfiStatusCode> {
+        let runtime_services = self.table.runtime_services as EfiPhysicalAddress;
+        let mut mapped = false;
+        let mut index = 0;
+        while let Some(desc) = memory_map.get_mut(index) {
+            if desc.is_runtime() {
+                desc.virtual_start = desc.physical_start + offset;
+                mapped |= desc.contains(runtime_services);
+            }
+            index += 1;
+        }
+        if !mapped {
+            return Err(EfiStatusCode::EfiNotFound);
+        }
+        let status = self.runtime_services().set_virtual_address_map(memory_map);
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(runtime_services + offset)
+    }
+}
+
+impl SystemTable<Boot> {
+    pub fn boot_services(&self) -> &EfiBootServices {
+        unsafe { self.table.boot_services.as_ref().unwrap() }
     }
 
     pub fn output_protocol(&self) -> &EfiSimpleTextOutputProtocol {
-        unsafe { self.con_out.as_ref().unwrap() }
+        unsafe { self.table.con_out.as_ref().unwrap() }
+    }
+
+    pub fn input_protocol(&self) -> &EfiSimpleTextInputProtocol {
+        unsafe { self.table.con_in.as_ref().unwrap() }
+    }
+
+    /// Fetches the final memory map into a buffer that survives boot
+    /// services and exits boot services.
+    ///
+    /// `print!` is disconnected from ConOut and the firmware's serial
+    /// driver first: output through them can allocate memory, which would
+    /// invalidate the map key. Serial output keeps going to COM1 if that
+    /// is the port in use.
+    pub fn exit_boot_services(
+        self,
+        image_handle: EfiHandle,
+    ) -> (SystemTable<Runtime>, MemoryDescriptorArray) {
+        set_console(None);
+        set_serial(get_serial().and_then(Serial::without_boot_services));
+        panic_report::exit_boot_services();
+        let boot_services = self.boot_services();
+        // Allocating the buffer itself may split a descriptor
+        let mut size = boot_services.memory_map_size(MEMORY_MAP_SPARE_DESCRIPTORS);
+        let mut buf = boot_services
+            .allocate_pool(MemoryType::EfiLoaderData, size)
+            .expect("failed to allocate the memory map");
+        let mut grown = false;
+        for _ in 0..EXIT_BOOT_SERVICES_ATTEMPTS {
+            let mem_desc_array = match boot_services.get_memory_descriptor_array(buf, size) {
+                Ok(mem_desc_array) => mem_desc_array,
+                // Allocations are only allowed before ExitBootServices was
+                // called for the first time, i.e. in the first iteration
+                Err(EfiStatusCode::EfiBufferTooSmall) if !grown => {
+                    grown = true;
+                    boot_services.free_pool(buf);
+                    size = boot_services.memory_map_size(MEMORY_MAP_SPARE_DESCRIPTORS);
+                    buf = boot_services
+                        .allocate_pool(MemoryType::EfiLoaderData, size)
+                        .expect("failed to allocate the memory map");
+                    continue;
+                }
+                Err(status) => panic!("failed to get the final memory map: {:?}", status),
+            };
+            grown = true;
+            let status = boot_services.exit_boot_services(image_handle, mem_desc_array.map_key());
+            if status.is_success() {
+                let runtime = SystemTable {
+                    table: self.table,
+                    _view: PhantomData,
+                };
+                return (runtime, mem_desc_array);
+            }
+            // The map changed in between; the firmware allows to retry
+            // with a fresh map key.
+        }
+        panic!(
+            "exit_boot_services: the memory map kept changing, gave up after {} attempts",
+            EXIT_BOOT_SERVICES_ATTEMPTS
+        );
     }
 }
 
 #[repr(C)]
 pub struct EfiConfigurationTable {
-    vendor_guid: EfiGuid,
+    pub vendor_guid: EfiGuid,
     pub vendor_table: *mut c_void,
 }
 
+/// EFI_MEMORY_ATTRIBUTES_TABLE: the permissions of the runtime regions of
+/// the memory map, split where code and data share a region. The entries
+/// follow the header.
+#[repr(C)]
+pub struct EfiMemoryAttributesTable {
+    pub version: u32,
+    pub number_of_entries: u32,
+    pub descriptor_size: u32,
+    pub flags: u32,
+}
+
+impl EfiMemoryAttributesTable {
+    pub fn entries(&self) -> MemoryDescriptorArray {
+        MemoryDescriptorArray::new(
+            unsafe { (self as *const Self).add(1) },
+            self.descriptor_size as usize,
+            self.number_of_entries as usize * self.descriptor_size as usize,
+            0,
+            self.version,
+        )
+    }
+}
+
 type FnPtr = u64;
 
 #[repr(C)]
@@ -67,15 +258,38 @@ pub struct EfiRuntimeServices {
     get_wakeup_time: FnPtr,
     set_wakeup_time: FnPtr,
     // Virtual Memory Services
-    set_virtual_address_map: FnPtr,
-    convert_pointer: FnPtr,
+    set_virtual_address_map: extern "efiapi" fn(
+        memory_map_size: usize,
+        descriptor_size: usize,
+        descriptor_version: u32,
+        virtual_map: *mut EfiMemoryDescriptor,
+    ) -> EfiStatus,
+    convert_pointer:
+        extern "efiapi" fn(debug_disposition: usize, address: *mut *mut c_void) -> EfiStatus,
     // Variable Services
-    get_variable: FnPtr,
+    get_variable: extern "efiapi" fn(
+        variable_name: *const CHAR16,
+        vendor_guid: &EfiGuid,
+        attributes: *mut u32,
+        data_size: &mut usize,
+        data: *mut c_void,
+    ) -> EfiStatus,
     get_next_variable_name: FnPtr,
-    set_variable: FnPtr,
+    set_variable: extern "efiapi" fn(
+        variable_name: *const CHAR16,
+        vendor_guid: &EfiGuid,
+        attributes: u32,
+        data_size: usize,
+        data: *const c_void,
+    ) -> EfiStatus,
     // Miscellaneous Services
     get_next_high_monotonic_count: FnPtr,
-    reset_system: FnPtr,
+    reset_system: extern "efiapi" fn(
+        reset_type: EfiResetType,
+        reset_status: EfiStatus,
+        data_size: usize,
+        reset_data: *const c_void,
+    ) -> !,
     // UEFI 2.0 Capsule Services
     update_capsule: FnPtr,
     query_capsule_capabilities: FnPtr,
@@ -83,6 +297,107 @@ pub struct EfiRuntimeServices {
     query_variable_info: FnPtr,
 }
 
+// Variable attributes
+pub const EFI_VARIABLE_NON_VOLATILE: u32 = 0x00000001;
+pub const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x00000002;
+pub const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x00000004;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+pub enum EfiResetType {
+    EfiResetCold,
+    EfiResetWarm,
+    EfiResetShutdown,
+    EfiResetPlatformSpecific,
+}
+
+impl EfiRuntimeServices {
+    /// Reads the variable into `data` and returns its attributes and size.
+    ///
+    /// `name` must be NUL terminated.
+    pub fn get_variable(
+        &self,
+        name: &[CHAR16],
+        vendor_guid: &EfiGuid,
+        data: &mut [u8],
+    ) -> Result<(u32, usize), EfiStatusCode> {
+        assert_eq!(
+            name.last(),
+            Some(&0),
+            "variable name must be NUL terminated"
+        );
+        let mut attributes = 0;
+        let mut data_size = data.len();
+        let status = (self.get_variable)(
+            name.as_ptr(),
+            vendor_guid,
+            &mut attributes,
+            &mut data_size,
+            data.as_mut_ptr().cast(),
+        );
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok((attributes, data_size))
+    }
+
+    /// Writes the variable, or deletes it when `data` is empty.
+    ///
+    /// `name` must be NUL terminated.
+    pub fn set_variable(
+        &self,
+        name: &[CHAR16],
+        vendor_guid: &EfiGuid,
+        attributes: u32,
+        data: &[u8],
+    ) -> EfiStatusCode {
+        assert_eq!(
+            name.last(),
+            Some(&0),
+            "variable name must be NUL terminated"
+        );
+        let status = (self.set_variable)(
+            name.as_ptr(),
+            vendor_guid,
+            attributes,
+            data.len(),
+            data.as_ptr().cast(),
+        );
+        status.try_into().unwrap()
+    }
+
+    pub fn reset_system(&self, reset_type: EfiResetType) -> ! {
+        (self.reset_system)(reset_type, 0, 0, core::ptr::null())
+    }
+
+    /// Only reachable through `SystemTable::set_virtual_address_map`.
+    fn set_virtual_address_map(&self, memory_map: &mut MemoryDescriptorArray) -> EfiStatusCode {
+        let status = (self.set_virtual_address_map)(
+            memory_map.map_size(),
+            memory_map.descriptor_size(),
+            memory_map.descriptor_version(),
+            memory_map.as_ptr().cast_mut(),
+        );
+        status.try_into().unwrap()
+    }
+
+    /// Converts `address` to the virtual address it has after
+    /// `SetVirtualAddressMap`.
+    ///
+    /// The firmware only accepts this while `SetVirtualAddressMap` is in
+    /// progress, i.e. from drivers notified of the address change. The
+    /// loader itself knows the mapping it handed out and needs no help.
+    pub fn convert_pointer(&self, address: &mut *mut c_void) -> Result<(), EfiStatusCode> {
+        let status = (self.convert_pointer)(0, address);
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(())
+    }
+}
+
 #[repr(C)]
 pub struct EfiBootServices {
     pub hdr: EfiTableHeader,
@@ -90,8 +405,13 @@ pub struct EfiBootServices {
     raise_tpl: FnPtr,
     restore_tpl: FnPtr,
     // Memory Services
-    allocate_pages: FnPtr,
-    free_pages: FnPtr,
+    allocate_pages: extern "efiapi" fn(
+        type_: u32,
+        memory_type: u32,
+        pages: usize,
+        memory: &mut EfiPhysicalAddress,
+    ) -> EfiStatus,
+    free_pages: extern "efiapi" fn(memory: EfiPhysicalAddress, pages: usize) -> EfiStatus,
     pub get_memory_map: extern "efiapi" fn(
         memory_map_size: &mut usize,
         memory_map: *mut EfiMemoryDescriptor,
@@ -99,8 +419,9 @@ pub struct EfiBootServices {
         descriptor_size: &mut usize,
         descriptor_version: &mut u32,
     ) -> EfiStatus,
-    allocate_pool: FnPtr,
-    free_pool: FnPtr,
+    allocate_pool:
+        extern "efiapi" fn(pool_type: u32, size: usize, buffer: &mut *mut c_void) -> EfiStatus,
+    free_pool: extern "efiapi" fn(buffer: *mut c_void) -> EfiStatus,
     // Event & Timer Services
     create_event: FnPtr,
     set_timer: FnPtr,
@@ -112,22 +433,47 @@ pub struct EfiBootServices {
     install_protocol_interface: FnPtr,
     reinstall_protocol_interface: FnPtr,
     uninstall_protocol_interface: FnPtr,
-    handle_protocol: FnPtr,
+    handle_protocol: extern "efiapi" fn(
+        handle: EfiHandle,
+        protocol: &EfiGuid,
+        interface: &mut *mut c_void,
+    ) -> EfiStatus,
     reserved: FnPtr,
     register_protocol_notify: FnPtr,
     locate_handle: FnPtr,
     locate_device_path: FnPtr,
     install_configuration_table: FnPtr,
     // Image Services
-    load_image: FnPtr,
-    start_image: FnPtr,
-    exit: FnPtr,
-    unload_image: FnPtr,
+    load_image: extern "efiapi" fn(
+        boot_policy: bool,
+        parent_image_handle: EfiHandle,
+        device_path: *const c_void,
+        source_buffer: *const c_void,
+        source_size: usize,
+        image_handle: &mut EfiHandle,
+    ) -> EfiStatus,
+    start_image: extern "efiapi" fn(
+        image_handle: EfiHandle,
+        exit_data_size: &mut usize,
+        exit_data: &mut *mut CHAR16,
+    ) -> EfiStatus,
+    exit: extern "efiapi" fn(
+        image_handle: EfiHandle,
+        exit_status: EfiStatus,
+        exit_data_size: usize,
+        exit_data: *const CHAR16,
+    ) -> EfiStatus,
+    unload_image: extern "efiapi" fn(image_handle: EfiHandle) -> EfiStatus,
     exit_boot_services: extern "efiapi" fn(image_handle: EfiHandle, map_key: usize) -> EfiStatus,
     // Miscellaneous Services
     get_next_monotonic_count: FnPtr,
-    stall: FnPtr,
-    set_watchdog_timer: FnPtr,
+    stall: extern "efiapi" fn(microseconds: usize) -> EfiStatus,
+    set_watchdog_timer: extern "efiapi" fn(
+        timeout: usize,
+        watchdog_code: u64,
+        data_size: usize,
+        watchdog_data: *const CHAR16,
+    ) -> EfiStatus,
     // DriverSupport Services
     connect_controller: FnPtr,
     disconnect_controller: FnPtr,
@@ -137,7 +483,13 @@ pub struct EfiBootServices {
     open_protocol_information: FnPtr,
     // Library Services
     protocols_per_handle: FnPtr,
-    locate_handle_buffer: FnPtr,
+    locate_handle_buffer: extern "efiapi" fn(
+        search_type: u32,
+        protocol: &EfiGuid,
+        search_key: *const c_void,
+        no_handles: &mut usize,
+        buffer: &mut *mut EfiHandle,
+    ) -> EfiStatus,
     locate_protocol: extern "efiapi" fn(
         protocol: &EfiGuid,
         registration: *const c_void,
@@ -163,6 +515,23 @@ pub struct EfiMemoryDescriptor {
     pub attribute: u64,
 }
 
+// Memory attributes
+pub const EFI_MEMORY_XP: u64 = 0x4000;
+pub const EFI_MEMORY_RO: u64 = 0x20000;
+/// The region must be mapped for runtime services to work
+pub const EFI_MEMORY_RUNTIME: u64 = 0x8000000000000000;
+
+impl EfiMemoryDescriptor {
+    pub fn is_runtime(&self) -> bool {
+        self.attribute & EFI_MEMORY_RUNTIME != 0
+    }
+
+    pub fn contains(&self, address: EfiPhysicalAddress) -> bool {
+        (self.physical_start..self.physical_start + self.number_of_pages * PAGE_SIZE as u64)
+            .contains(&address)
+    }
+}
+
 impl core::fmt::Display for EfiMemoryDescriptor {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_fmt(format_args!(
@@ -180,8 +549,8 @@ pub struct EfiSimpleTextOutputProtocol {
     pub reset: extern "efiapi" fn(&Self, bool) -> EfiStatus,
     pub output_string: extern "efiapi" fn(&Self, *const CHAR16) -> EfiStatus,
     query_mode: extern "efiapi" fn(&Self, usize, *mut usize, *mut usize) -> EfiStatus,
-    set_mode: FnPtr,
-    set_attribute: FnPtr,
+    set_mode: extern "efiapi" fn(&Self, usize) -> EfiStatus,
+    set_attribute: extern "efiapi" fn(&Self, usize) -> EfiStatus,
     clear_screen: extern "efiapi" fn(&Self) -> EfiStatus,
     pub set_cursor_position: extern "efiapi" fn(&Self, usize, usize) -> EfiStatus,
     enable_cursor: extern "efiapi" fn(&Self, bool) -> EfiStatus,
@@ -206,8 +575,52 @@ pub struct EfiSimpleTextInputProtocol {
     pub wait_for_key: *mut c_void,
 }
 
+// Text attributes for set_attribute: a foreground colour, optionally
+// EFI_BRIGHT, or'ed with a background colour
+pub const EFI_BLACK: usize = 0x00;
+pub const EFI_BLUE: usize = 0x01;
+pub const EFI_GREEN: usize = 0x02;
+pub const EFI_CYAN: usize = 0x03;
+pub const EFI_RED: usize = 0x04;
+pub const EFI_MAGENTA: usize = 0x05;
+pub const EFI_BROWN: usize = 0x06;
+pub const EFI_LIGHTGRAY: usize = 0x07;
+pub const EFI_BRIGHT: usize = 0x08;
+pub const EFI_DARKGRAY: usize = 0x08;
+pub const EFI_LIGHTBLUE: usize = 0x09;
+pub const EFI_LIGHTGREEN: usize = 0x0a;
+pub const EFI_LIGHTCYAN: usize = 0x0b;
+pub const EFI_LIGHTRED: usize = 0x0c;
+pub const EFI_LIGHTMAGENTA: usize = 0x0d;
+pub const EFI_YELLOW: usize = 0x0e;
+pub const EFI_WHITE: usize = 0x0f;
+
+pub const EFI_BACKGROUND_BLACK: usize = 0x00;
+pub const EFI_BACKGROUND_BLUE: usize = 0x10;
+pub const EFI_BACKGROUND_GREEN: usize = 0x20;
+pub const EFI_BACKGROUND_CYAN: usize = 0x30;
+pub const EFI_BACKGROUND_RED: usize = 0x40;
+pub const EFI_BACKGROUND_MAGENTA: usize = 0x50;
+pub const EFI_BACKGROUND_BROWN: usize = 0x60;
+pub const EFI_BACKGROUND_LIGHTGRAY: usize = 0x70;
+
+impl EfiSimpleTextInputProtocol {
+    /// Returns the next key in the input buffer without waiting.
+    pub fn read_key_stroke(&self) -> Option<EfiInputKey> {
+        let mut key = EfiInputKey {
+            scan_code: 0,
+            unicode_char: 0,
+        };
+        let status = (self.read_key_stroke)(self, &mut key);
+        if EfiStatusCode::try_from(status).ok()? != EfiStatusCode::EfiSuccess {
+            return None;
+        }
+        Some(key)
+    }
+}
+
 impl EfiBootServices {
-    pub fn get_memory_map(&self, size: usize, map: &mut MemoryMap) -> Result<EfiStatusCode, &str> {
+    pub fn get_memory_map(&self, size: usize, map: &mut MemoryMap) -> EfiStatusCode {
         map.memory_map_size = size;
         let status = (self.get_memory_map)(
             &mut map.memory_map_size,
@@ -217,38 +630,185 @@ impl EfiBootServices {
             &mut map.descriptor_version,
         );
         if EfiStatusCode::try_from(status).unwrap() == EfiStatusCode::EfiBufferTooSmall {
-            println!("buffer too small");
+            debug!(
+                "get_memory_map: buffer too small, {} bytes needed",
+                map.memory_map_size
+            );
         }
-        Ok(status.try_into().unwrap())
+        status.try_into().unwrap()
     }
 
+    /// Bytes a buffer needs to hold the current memory map and `spare`
+    /// more descriptors.
+    pub fn memory_map_size(&self, spare: usize) -> usize {
+        let mut map = MemoryMap::new(core::ptr::null_mut::<u8>(), 0);
+        // Fails with EfiBufferTooSmall and the size needed
+        let _ = self.get_memory_map(0, &mut map);
+        map.memory_map_size + spare * map.descriptor_size
+    }
+
+    /// Reads the memory map into the buffer at `memmap_buf_ptr`. Fails with
+    /// `EfiBufferTooSmall` if it does not fit in `size` bytes.
     pub fn get_memory_descriptor_array<T>(
         &self,
         memmap_buf_ptr: *mut T,
         size: usize,
-    ) -> MemoryDescriptorArray {
+    ) -> Result<MemoryDescriptorArray, EfiStatusCode> {
         let mut map = MemoryMap::new(memmap_buf_ptr, size);
-        self.get_memory_map(size, &mut map).unwrap();
-
-        MemoryDescriptorArray::new(
+        let status = self.get_memory_map(size, &mut map);
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(MemoryDescriptorArray::new(
             memmap_buf_ptr,
             map.descriptor_size,
             map.memory_map_size,
             map.map_key,
-        )
+            map.descriptor_version,
+        ))
     }
 
-    pub fn exit_boot_services(
-        &self,
-        image_handle: EfiHandle,
-        map_key: usize,
-    ) -> Result<EfiStatusCode, Error> {
+    /// Only reachable through `SystemTable::exit_boot_services`.
+    fn exit_boot_services(&self, image_handle: EfiHandle, map_key: usize) -> EfiStatusCode {
         let status = (self.exit_boot_services)(image_handle, map_key);
-        println!(
-            "exit_boot_services: {:?}",
-            EfiStatusCode::try_from(status).unwrap()
+        status.try_into().unwrap()
+    }
+
+    pub fn allocate_pages(
+        &self,
+        allocate_type: EfiAllocateType,
+        memory_type: MemoryType,
+        pages: usize,
+    ) -> Result<EfiPhysicalAddress, EfiStatusCode> {
+        let mut memory = match allocate_type {
+            EfiAllocateType::AllocateAnyPages => 0,
+            EfiAllocateType::AllocateMaxAddress(addr) => addr,
+            EfiAllocateType::AllocateAddress(addr) => addr,
+        };
+        let status =
+            (self.allocate_pages)(allocate_type.into(), memory_type as u32, pages, &mut memory);
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(memory)
+    }
+
+    pub fn free_pages(&self, memory: EfiPhysicalAddress, pages: usize) -> EfiStatusCode {
+        let status = (self.free_pages)(memory, pages);
+        status.try_into().unwrap()
+    }
+
+    pub fn allocate_pool(
+        &self,
+        pool_type: MemoryType,
+        size: usize,
+    ) -> Result<*mut u8, EfiStatusCode> {
+        let mut buffer = core::ptr::null_mut();
+        let status = (self.allocate_pool)(pool_type as u32, size, &mut buffer);
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(buffer.cast())
+    }
+
+    pub fn free_pool<T>(&self, buffer: *mut T) -> EfiStatusCode {
+        let status = (self.free_pool)(buffer.cast());
+        status.try_into().unwrap()
+    }
+
+    /// Returns the protocol interface installed on `handle`, or the status
+    /// the firmware reported (e.g. `EfiUnsupported` if it is not installed).
+    pub fn handle_protocol<T: HasGuid>(&self, handle: EfiHandle) -> Result<&T, EfiStatusCode> {
+        self.handle_protocol_mut(handle)
+            .map(|interface| &*interface)
+    }
+
+    /// Like `handle_protocol`, for interfaces whose fields the caller
+    /// fills in, such as the load options of a child image.
+    // The interface belongs to the firmware, not to the boot services table
+    #[allow(clippy::mut_from_ref)]
+    pub fn handle_protocol_mut<T: HasGuid>(
+        &self,
+        handle: EfiHandle,
+    ) -> Result<&mut T, EfiStatusCode> {
+        let mut interface = core::ptr::null_mut();
+        let status = (self.handle_protocol)(handle, T::get_guid(), &mut interface);
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        unsafe {
+            interface
+                .cast::<T>()
+                .as_mut()
+                .ok_or(EfiStatusCode::EfiNotFound)
+        }
+    }
+
+    /// Loads the image in `source` without starting it. `device_path` is
+    /// where it came from, which the child may use to find its files.
+    pub fn load_image(
+        &self,
+        parent_image_handle: EfiHandle,
+        device_path: *const c_void,
+        source: &[u8],
+    ) -> Result<EfiHandle, EfiStatusCode> {
+        let mut image_handle = core::ptr::null_mut();
+        let status = (self.load_image)(
+            false,
+            parent_image_handle,
+            device_path,
+            source.as_ptr().cast(),
+            source.len(),
+            &mut image_handle,
         );
-        Ok(status.try_into().unwrap())
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(image_handle)
+    }
+
+    /// Runs a loaded image until it returns or calls `exit`, and returns
+    /// its exit status, which need not be a known `EfiStatusCode`.
+    ///
+    /// Applications are unloaded by the firmware when they return.
+    pub fn start_image(&self, image_handle: EfiHandle) -> EfiStatus {
+        let mut exit_data_size = 0;
+        let mut exit_data = core::ptr::null_mut();
+        let status = (self.start_image)(image_handle, &mut exit_data_size, &mut exit_data);
+        if !exit_data.is_null() {
+            self.free_pool(exit_data);
+        }
+        status
+    }
+
+    /// Terminates `image_handle`, the running image, returning
+    /// `exit_status` to whoever started it. Only returns on failure.
+    pub fn exit(&self, image_handle: EfiHandle, exit_status: EfiStatus) -> EfiStatusCode {
+        let status = (self.exit)(image_handle, exit_status, 0, core::ptr::null());
+        status.try_into().unwrap()
+    }
+
+    pub fn unload_image(&self, image_handle: EfiHandle) -> EfiStatusCode {
+        let status = (self.unload_image)(image_handle);
+        status.try_into().unwrap()
+    }
+
+    pub fn stall(&self, microseconds: usize) -> EfiStatusCode {
+        let status = (self.stall)(microseconds);
+        status.try_into().unwrap()
+    }
+
+    /// Arms the watchdog timer, or disables it when `timeout` is 0.
+    ///
+    /// The firmware resets the machine 5 minutes after the loader was
+    /// started unless the watchdog is disabled, e.g. while waiting for input.
+    pub fn set_watchdog_timer(&self, timeout: usize) -> EfiStatusCode {
+        let status = (self.set_watchdog_timer)(timeout, 0, 0, core::ptr::null());
+        status.try_into().unwrap()
     }
 
     pub fn graphics_output_protocol(&self) -> &EfiGraphicsOutputProtocol {
@@ -262,26 +822,68 @@ impl EfiBootServices {
     }
 
     pub fn locate_protocol<T: HasGuid>(&self) -> &T {
+        self.try_locate_protocol()
+            .expect("provided pointer was null")
+    }
+
+    /// The handles `T` is installed on, in a pool buffer that must be
+    /// freed with `free_pool`.
+    pub fn locate_handle_buffer<T: HasGuid>(&self) -> Result<&[EfiHandle], EfiStatusCode> {
+        const BY_PROTOCOL: u32 = 2;
+        let mut count = 0;
+        let mut buffer = core::ptr::null_mut();
+        let status = (self.locate_handle_buffer)(
+            BY_PROTOCOL,
+            T::get_guid(),
+            core::ptr::null(),
+            &mut count,
+            &mut buffer,
+        );
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok(unsafe { core::slice::from_raw_parts(buffer, count) })
+    }
+
+    /// Like `locate_protocol`, for protocols the firmware may not provide.
+    pub fn try_locate_protocol<T: HasGuid>(&self) -> Result<&T, EfiStatusCode> {
         let ptr = core::ptr::null();
-        println!("{:X?}", T::get_guid());
-        (self.locate_protocol)(T::get_guid(), core::ptr::null(), &ptr);
-        unsafe { ptr.cast::<T>().as_ref().expect("provided pointer was null") }
+        trace!("locate_protocol: {:X?}", T::get_guid());
+        let status = (self.locate_protocol)(T::get_guid(), core::ptr::null(), &ptr);
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        unsafe { ptr.cast::<T>().as_ref().ok_or(EfiStatusCode::EfiNotFound) }
     }
 }
 
 impl EfiSimpleTextOutputProtocol {
+    /// Prints `msg` in pieces of `OutputChunk` characters, stopping at
+    /// the first error.
     pub fn output_string(&self, msg: &str) -> EfiStatusCode {
-        let status = (self.output_string)(
-            self,
-            // msg.encode_utf16().collect::<Vec<u16, U4096>>().as_ptr(),
-            dyn_utf16_ptr!(msg),
-        );
+        let result = for_each_chunk::<OutputChunk, _>(msg, |chunk| {
+            let status = self.output_cstr16(chunk);
+            if status.is_err() {
+                return Err(status);
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => EfiStatusCode::EfiSuccess,
+            Err(status) => status,
+        }
+    }
+
+    pub fn output_cstr16(&self, msg: &CStr16) -> EfiStatusCode {
+        let status = (self.output_string)(self, msg.as_ptr());
         status.try_into().unwrap()
     }
 
     pub fn reset(&self, b: bool) -> EfiStatusCode {
         let status = (self.reset)(self, b);
-        println!("reset: {:?}", EfiStatusCode::try_from(status));
+        debug!("reset: {:?}", EfiStatusCode::try_from(status));
         status.try_into().unwrap()
     }
 
@@ -294,18 +896,93 @@ impl EfiSimpleTextOutputProtocol {
         (self.enable_cursor)(self, b);
     }
 
-    pub fn change_column(&self) {
-        let column;
-        let row;
+    pub fn set_attribute(&self, attribute: usize) -> EfiStatusCode {
+        let status = (self.set_attribute)(self, attribute);
+        status.try_into().unwrap()
+    }
+
+    pub fn set_cursor_position(&self, column: usize, row: usize) -> EfiStatusCode {
+        let status = (self.set_cursor_position)(self, column, row);
+        status.try_into().unwrap()
+    }
+
+    /// The current attribute, e.g. to restore it after `set_attribute`.
+    pub fn attribute(&self) -> usize {
+        unsafe { (*self.mode).attribute as usize }
+    }
+
+    /// Returns the (column, row) of the cursor.
+    pub fn cursor_position(&self) -> (usize, usize) {
         unsafe {
-            column = (*(self.mode)).cursor_column as usize;
-            row = (*(self.mode)).cursor_row as usize;
+            (
+                (*self.mode).cursor_column as usize,
+                (*self.mode).cursor_row as usize,
+            )
         }
-        (self.set_cursor_position)(self, column + 1, row);
+    }
+
+    /// Returns the (columns, rows) of text mode `mode`. Fails with
+    /// `EfiUnsupported` for modes the output device cannot show.
+    pub fn query_mode(&self, mode: usize) -> Result<(usize, usize), EfiStatusCode> {
+        let mut columns = 0;
+        let mut rows = 0;
+        let status = (self.query_mode)(self, mode, &mut columns, &mut rows);
+        let status = EfiStatusCode::try_from(status).unwrap();
+        if status.is_err() {
+            return Err(status);
+        }
+        Ok((columns, rows))
+    }
+
+    /// Switches to text mode `mode`, which also clears the screen.
+    pub fn set_mode(&self, mode: usize) -> EfiStatusCode {
+        let status = (self.set_mode)(self, mode);
+        status.try_into().unwrap()
+    }
+
+    /// The current text mode.
+    pub fn mode(&self) -> usize {
+        unsafe { (*self.mode).mode as usize }
+    }
+
+    /// Iterates over the supported text modes as (mode, columns, rows).
+    pub fn modes(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        let max_mode = unsafe { (*self.mode).max_mode as usize };
+        (0..max_mode).filter_map(|mode| {
+            let (columns, rows) = self.query_mode(mode).ok()?;
+            Some((mode, columns, rows))
+        })
+    }
+
+    /// Switches to the mode with the most characters on screen, unless it
+    /// is already set, and returns its (columns, rows).
+    pub fn select_largest_mode(&self) -> Result<(usize, usize), EfiStatusCode> {
+        let (mode, columns, rows) = self
+            .modes()
+            .max_by_key(|&(_, columns, rows)| columns * rows)
+            .ok_or(EfiStatusCode::EfiUnsupported)?;
+        if mode != self.mode() {
+            let status = self.set_mode(mode);
+            if status.is_err() {
+                return Err(status);
+            }
+        }
+        Ok((columns, rows))
+    }
+
+    /// Returns the (columns, rows) of the current text mode.
+    pub fn size(&self) -> Result<(usize, usize), EfiStatusCode> {
+        self.query_mode(self.mode())
+    }
+
+    pub fn change_column(&self) {
+        let (column, row) = self.cursor_position();
+        self.set_cursor_position(column + 1, row);
     }
 }
 
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct EfiInputKey {
     pub scan_code: u16,
     pub unicode_char: CHAR16,
@@ -326,7 +1003,7 @@ pub struct EfiTime {
     pad2: u8,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum EfiStatusCode {
     EfiSuccess,
     EfiLoadError,
@@ -484,7 +1161,25 @@ impl TryFrom<EfiStatus> for EfiStatusCode {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy)]
+pub enum EfiAllocateType {
+    AllocateAnyPages,
+    AllocateMaxAddress(EfiPhysicalAddress),
+    AllocateAddress(EfiPhysicalAddress),
+}
+
+impl From<EfiAllocateType> for u32 {
+    fn from(allocate_type: EfiAllocateType) -> Self {
+        match allocate_type {
+            EfiAllocateType::AllocateAnyPages => 0,
+            EfiAllocateType::AllocateMaxAddress(_) => 1,
+            EfiAllocateType::AllocateAddress(_) => 2,
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MemoryType {
     EfiReservedMemoryType,
     EfiLoaderCode,
@@ -503,6 +1198,9 @@ pub enum MemoryType {
     EfiPersistentMemory,
     EfiUnacceptedMemoryType,
     EfiMaxMemoryType,
+    // 0x80000000..=0xffffffff are reserved for use by OS loaders
+    /// Initial ramdisk handed to the kernel
+    LemolaRamdisk = 0x80000000,
 }
 
 impl TryFrom<u32> for MemoryType {
@@ -528,6 +1226,7 @@ impl TryFrom<u32> for MemoryType {
             14 => EfiPersistentMemory,
             15 => EfiUnacceptedMemoryType,
             16 => EfiMaxMemoryType,
+            0x80000000 => LemolaRamdisk,
             _ => return Err(Error),
         };
         Ok(mem_type)
@@ -1,24 +1,55 @@
+use crate::ansi::{self, Segment};
+use crate::boot_log;
+use crate::protocols::{EfiFileProtocol, FileAttributes, OpenMode};
+use crate::serial::Serial;
 use crate::uefi::*;
 use core::cell::Cell;
 use core::fmt::Error;
+use heapless::consts::U256;
+use heapless::String;
 
-pub static mut WRITER: Writer = Writer {
+static mut WRITER: Writer = Writer {
     output_protocol: Cell::new(None),
+    serial: Cell::new(None),
 };
 
+/// Writes to ConOut and mirrors everything to serial and the boot log.
 pub struct Writer {
     pub output_protocol: Cell<Option<&'static EfiSimpleTextOutputProtocol>>,
+    pub serial: Cell<Option<Serial>>,
 }
 
 unsafe impl Sync for Writer {}
 
 impl core::fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        if let Some(output_protcol) = self.output_protocol.get() {
-            output_protcol.output_string(s);
-            return Ok(());
+        boot_log::append(s);
+        let output_protocol = self.output_protocol.get();
+        let serial = self.serial.get();
+        if let Some(output_protcol) = output_protocol {
+            write_console(output_protcol, s);
+        }
+        if let Some(serial) = serial {
+            serial.write_str(s);
+        }
+        if output_protocol.is_none() && serial.is_none() {
+            return Err(Error);
+        }
+        Ok(())
+    }
+}
+
+/// Outputs `s` to ConOut, turning colour escapes into attributes.
+pub(crate) fn write_console(output_protocol: &EfiSimpleTextOutputProtocol, s: &str) {
+    for segment in ansi::segments(s) {
+        match segment {
+            Segment::Text(text) => {
+                output_protocol.output_string(text);
+            }
+            Segment::Sgr(params) => {
+                output_protocol.set_attribute(ansi::apply_sgr(output_protocol.attribute(), params));
+            }
         }
-        Err(Error)
     }
 }
 
@@ -33,6 +64,8 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\r\n", format_args!($($arg)*)));
 }
 
+/// Reads the memory map into a 16 KiB stack buffer. Fails with
+/// `EfiBufferTooSmall` on machines with larger maps.
 #[macro_export]
 macro_rules! mem_desc {
     ($boot_services:expr) => {{
@@ -47,12 +80,166 @@ macro_rules! mem_desc {
     }};
 }
 
+/// Points `print!` at ConOut and serial. `SystemTable::exit_boot_services`
+/// disconnects ConOut again, after which output only goes to serial.
+pub fn init_writer(system_table: &SystemTable<Boot>) {
+    set_console(unsafe { system_table.table.con_out.as_ref() });
+    let boot_services = unsafe { &*system_table.table.boot_services };
+    set_serial(Some(Serial::locate(boot_services)));
+}
+
+/// The writer is only touched through `Cell`s from here
+fn writer() -> &'static Writer {
+    unsafe { &*core::ptr::addr_of!(WRITER) }
+}
+
+pub(crate) fn set_console(output_protocol: Option<&'static EfiSimpleTextOutputProtocol>) {
+    writer().output_protocol.set(output_protocol);
+}
+
+pub(crate) fn get_serial() -> Option<Serial> {
+    writer().serial.get()
+}
+
+pub(crate) fn set_serial(serial: Option<Serial>) {
+    writer().serial.set(serial);
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
     unsafe {
-        WRITER.write_fmt(args).unwrap();
+        // Fails only without a console
+        let _ = WRITER.write_fmt(args);
+    }
+}
+
+/// Reads the whole file at `path` into a newly allocated `EfiLoaderData`
+/// pool buffer. The caller owns the buffer and may `free_pool` it.
+pub fn read_file(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+    path: &str,
+) -> Result<&'static mut [u8], EfiStatusCode> {
+    let file = root_dir.open(
+        path,
+        OpenMode::EfiFileModeRead,
+        FileAttributes::EfiFileReadOnly,
+    )?;
+    let result = read_to_end(boot_services, file);
+    file.close();
+    result
+}
+
+fn read_to_end(
+    boot_services: &EfiBootServices,
+    file: &EfiFileProtocol,
+) -> Result<&'static mut [u8], EfiStatusCode> {
+    let size = file.file_size()? as usize;
+    let buf = boot_services.allocate_pool(MemoryType::EfiLoaderData, size.max(1))?;
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf, size) };
+    if let Err(status) = read_exact(file, buf) {
+        boot_services.free_pool(buf.as_mut_ptr());
+        return Err(status);
+    }
+    Ok(buf)
+}
+
+/// Replaces the file at `path` with `data`, creating it if needed.
+pub fn write_file(
+    root_dir: &EfiFileProtocol,
+    path: &str,
+    data: &[u8],
+) -> Result<(), EfiStatusCode> {
+    delete_file(root_dir, path)?;
+    let file = root_dir.open(
+        path,
+        OpenMode::EfiFiileModeCreate,
+        FileAttributes::EfiFileArchive,
+    )?;
+    let result = write_all(file, data);
+    let status = file.close();
+    result?;
+    if status.is_err() {
+        return Err(status);
+    }
+    Ok(())
+}
+
+/// Deletes the file at `path`; a missing file is not an error.
+pub fn delete_file(root_dir: &EfiFileProtocol, path: &str) -> Result<(), EfiStatusCode> {
+    let file = match root_dir.open(
+        path,
+        OpenMode::EfiFileModeWrite,
+        FileAttributes::EfiFileArchive,
+    ) {
+        Ok(file) => file,
+        Err(EfiStatusCode::EfiNotFound) => return Ok(()),
+        Err(status) => return Err(status),
+    };
+    match file.delete() {
+        EfiStatusCode::EfiSuccess => Ok(()),
+        status => Err(status),
+    }
+}
+
+pub fn write_all(file: &EfiFileProtocol, mut buf: &[u8]) -> Result<(), EfiStatusCode> {
+    while !buf.is_empty() {
+        match file.write(buf)? {
+            0 => return Err(EfiStatusCode::EfiVolumeFull),
+            written => buf = &buf[written..],
+        }
     }
+    Ok(())
+}
+
+/// `path` followed by `suffix`, e.g. the path of a detached signature,
+/// or `None` if that is too long.
+pub fn path_with_suffix(path: &str, suffix: &str) -> Option<String<U256>> {
+    let mut result = String::new();
+    result.push_str(path).ok()?;
+    result.push_str(suffix).ok()?;
+    Some(result)
+}
+
+/// Reads the whole file at `path` into newly allocated pages of
+/// `memory_type` and returns their address together with the file size.
+pub fn read_file_to_pages(
+    boot_services: &EfiBootServices,
+    root_dir: &EfiFileProtocol,
+    path: &str,
+    memory_type: MemoryType,
+) -> Result<(EfiPhysicalAddress, usize), EfiStatusCode> {
+    let file = root_dir.open(
+        path,
+        OpenMode::EfiFileModeRead,
+        FileAttributes::EfiFileReadOnly,
+    )?;
+    let result = file.file_size().and_then(|size| {
+        let size = size as usize;
+        let pages = size.div_ceil(PAGE_SIZE).max(1);
+        let base =
+            boot_services.allocate_pages(EfiAllocateType::AllocateAnyPages, memory_type, pages)?;
+        let buf = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, size) };
+        if let Err(status) = read_exact(file, buf) {
+            boot_services.free_pages(base, pages);
+            return Err(status);
+        }
+        Ok((base, size))
+    });
+    file.close();
+    result
+}
+
+fn read_exact(file: &EfiFileProtocol, buf: &mut [u8]) -> Result<(), EfiStatusCode> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => return Err(EfiStatusCode::EfiEndOfFile),
+            read => filled += read,
+        }
+    }
+    Ok(())
 }
 
 #[repr(C)]
@@ -83,6 +270,7 @@ pub struct MemoryDescriptorArray {
     mem_desc_size: usize,
     mem_map_size: usize,
     map_key: usize,
+    descriptor_version: u32,
 }
 
 impl MemoryDescriptorArray {
@@ -100,17 +288,33 @@ impl MemoryDescriptorArray {
         }
     }
 
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut EfiMemoryDescriptor> {
+        if self.mem_map_size <= index * self.mem_desc_size {
+            return None;
+        }
+        unsafe {
+            self.mem_desc_head
+                .cast::<u8>()
+                .add(index * self.mem_desc_size)
+                .cast::<EfiMemoryDescriptor>()
+                .cast_mut()
+                .as_mut()
+        }
+    }
+
     pub fn new<T>(
         mem_desc_head: *const T,
         mem_desc_size: usize,
         mem_map_size: usize,
         map_key: usize,
+        descriptor_version: u32,
     ) -> MemoryDescriptorArray {
         MemoryDescriptorArray {
             mem_desc_head: mem_desc_head.cast::<EfiMemoryDescriptor>(),
             mem_desc_size,
             mem_map_size,
             map_key,
+            descriptor_version,
         }
     }
 
@@ -118,6 +322,22 @@ impl MemoryDescriptorArray {
         self.map_key
     }
 
+    pub fn as_ptr(&self) -> *const EfiMemoryDescriptor {
+        self.mem_desc_head
+    }
+
+    pub fn descriptor_size(&self) -> usize {
+        self.mem_desc_size
+    }
+
+    pub fn map_size(&self) -> usize {
+        self.mem_map_size
+    }
+
+    pub fn descriptor_version(&self) -> u32 {
+        self.descriptor_version
+    }
+
     pub fn iter(self) -> MemoryDescriptorIterator {
         MemoryDescriptorIterator {
             mem_desc_array: self,
@@ -135,8 +355,8 @@ impl Iterator for MemoryDescriptorIterator {
     type Item = &'static EfiMemoryDescriptor;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let desc = self.mem_desc_array.get(self.index);
         self.index += 1;
-
-        self.mem_desc_array.get(self.index + 1)
+        desc
     }
 }
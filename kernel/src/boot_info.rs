@@ -0,0 +1,163 @@
+// Keep in sync with bootloader/src/boot_info.rs
+
+// Not every field is consumed by the kernel yet
+#![allow(dead_code)]
+
+pub const CMDLINE_MAX: usize = 512;
+pub const RNG_SEED_SIZE: usize = 32;
+
+/// Start of the canonical higher half
+pub const HIGHER_HALF_START: u64 = 0xffff_8000_0000_0000;
+/// All physical memory, including the frame buffer, is mapped at this
+/// offset. Physical addresses in `BootInfo` are accessed through it.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = HIGHER_HALF_START;
+
+/// Memory type of the pages holding the ramdisk in the memory map.
+/// Anything but the usable UEFI memory types must never be handed out by
+/// the frame allocator.
+pub const LEMOLA_RAMDISK_MEMORY_TYPE: u32 = 0x80000000;
+
+/// The kernel stack grows down from here. The page below its lowest page
+/// is never mapped, so that an overflow faults instead of corrupting
+/// memory.
+pub const KERNEL_STACK_TOP: u64 = 0xffff_ff00_0000_0000;
+
+/// Every `EFI_MEMORY_RUNTIME` region is mapped at its physical address
+/// plus this offset, which is also the virtual address the firmware was
+/// told with `SetVirtualAddressMap`.
+pub const EFI_RUNTIME_OFFSET: u64 = 0xffff_fc00_0000_0000;
+
+#[repr(C)]
+pub struct BootInfo {
+    pub frame_buffer: FrameBufferInfo,
+    pub memory_map: MemoryMapInfo,
+    pub ramdisk: RamdiskInfo,
+    /// Physical address of the PML4 the kernel was entered with
+    pub page_table: u64,
+    pub physical_memory_offset: u64,
+    pub kernel_stack: KernelStackInfo,
+    /// Difference between the address the kernel runs at and the address
+    /// it is linked at, 0 unless it was relocated
+    pub kernel_slide: u64,
+    /// Early entropy for the kernel's random number generator
+    pub rng_seed: [u8; RNG_SEED_SIZE],
+    pub rng_seed_source: EntropySource,
+    /// Virtual address of the UEFI runtime services table, already
+    /// switched to virtual addressing, or 0 if they are unavailable
+    pub runtime_services: u64,
+    pub cmdline_len: usize,
+    pub cmdline: [u8; CMDLINE_MAX],
+}
+
+impl BootInfo {
+    pub fn cmdline(&self) -> &str {
+        core::str::from_utf8(&self.cmdline[..self.cmdline_len]).unwrap_or("")
+    }
+
+    pub fn phys_to_virt(&self, phys: u64) -> u64 {
+        phys + self.physical_memory_offset
+    }
+
+    pub fn ramdisk(&self) -> Option<&'static [u8]> {
+        if self.ramdisk.len == 0 {
+            return None;
+        }
+        unsafe {
+            Some(core::slice::from_raw_parts(
+                self.phys_to_virt(self.ramdisk.base) as *const u8,
+                self.ramdisk.len,
+            ))
+        }
+    }
+
+    pub fn memory_map(&self) -> impl Iterator<Item = &'static MemoryDescriptor> {
+        let buffer = self.phys_to_virt(self.memory_map.buffer);
+        let descriptor_size = self.memory_map.descriptor_size;
+        (0..self.memory_map.map_size / descriptor_size).map(move |i| unsafe {
+            &*((buffer as usize + i * descriptor_size) as *const MemoryDescriptor)
+        })
+    }
+}
+
+/// The UEFI memory map as returned by GetMemoryMap after the last
+/// allocation of the loader.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryMapInfo {
+    pub buffer: u64,
+    pub map_size: usize,
+    pub descriptor_size: usize,
+}
+
+/// EFI_MEMORY_DESCRIPTOR
+#[repr(C)]
+#[derive(Debug)]
+pub struct MemoryDescriptor {
+    pub type_: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+impl MemoryDescriptor {
+    /// Whether the frame allocator may hand out these pages.
+    ///
+    /// Loader code and data are not usable since they hold the kernel
+    /// image, the boot info and the memory map itself.
+    pub fn is_usable(&self) -> bool {
+        const EFI_BOOT_SERVICES_CODE: u32 = 3;
+        const EFI_BOOT_SERVICES_DATA: u32 = 4;
+        const EFI_CONVENTIONAL_MEMORY: u32 = 7;
+        matches!(
+            self.type_,
+            EFI_BOOT_SERVICES_CODE | EFI_BOOT_SERVICES_DATA | EFI_CONVENTIONAL_MEMORY
+        )
+    }
+}
+
+/// Where `BootInfo::rng_seed` came from. With `None` the seed is all
+/// zeroes and must not be relied on.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntropySource {
+    None,
+    EfiRng,
+    Rdseed,
+    Rdrand,
+}
+
+/// Virtual range of the stack the kernel is entered on.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelStackInfo {
+    pub bottom: u64,
+    pub top: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RamdiskInfo {
+    pub base: u64,
+    pub len: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FrameBufferInfo {
+    pub base: u64,
+    pub size: usize,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixels_per_scan_line: u32,
+    pub pixel_format: PixelFormat,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+    Bitmask,
+    BltOnly,
+}
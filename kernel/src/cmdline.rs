@@ -0,0 +1,164 @@
+use core::cell::UnsafeCell;
+
+/// A parameter that can be set from the kernel command line.
+///
+/// Parameters are declared with [`kernel_param!`] anywhere in the kernel
+/// and collected into the `kernel_params` linker section, so this module
+/// does not need to know about them.
+pub trait KernelParam: Sync {
+    fn name(&self) -> &'static str;
+    /// `value` is `None` for a bare flag such as `nosmp`.
+    fn set(&self, value: Option<&'static str>) -> Result<(), ParamError>;
+}
+
+#[derive(Debug)]
+pub enum ParamError {
+    MissingValue,
+    InvalidValue,
+}
+
+/// Types a parameter can have.
+pub trait ParamValue: Sized + Copy + 'static {
+    fn parse(value: Option<&'static str>) -> Result<Self, ParamError>;
+}
+
+impl ParamValue for bool {
+    fn parse(value: Option<&'static str>) -> Result<Self, ParamError> {
+        match value {
+            None | Some("1") | Some("on") | Some("yes") | Some("true") => Ok(true),
+            Some("0") | Some("off") | Some("no") | Some("false") => Ok(false),
+            Some(_) => Err(ParamError::InvalidValue),
+        }
+    }
+}
+
+impl ParamValue for &'static str {
+    fn parse(value: Option<&'static str>) -> Result<Self, ParamError> {
+        value.ok_or(ParamError::MissingValue)
+    }
+}
+
+macro_rules! impl_param_value_for_int {
+    ($($t:ty),*) => {
+        $(
+            impl ParamValue for $t {
+                fn parse(value: Option<&'static str>) -> Result<Self, ParamError> {
+                    value
+                        .ok_or(ParamError::MissingValue)?
+                        .parse()
+                        .map_err(|_| ParamError::InvalidValue)
+                }
+            }
+        )*
+    };
+}
+
+impl_param_value_for_int!(u8, u16, u32, u64, usize);
+
+/// Storage of a typed parameter with its default and validation.
+pub struct Param<T: ParamValue> {
+    name: &'static str,
+    value: UnsafeCell<T>,
+    validate: fn(&T) -> bool,
+}
+
+// Parameters are only written while parsing the command line, before
+// anything else runs.
+unsafe impl<T: ParamValue> Sync for Param<T> {}
+
+impl<T: ParamValue> Param<T> {
+    pub const fn new(name: &'static str, default: T, validate: fn(&T) -> bool) -> Self {
+        Self {
+            name,
+            value: UnsafeCell::new(default),
+            validate,
+        }
+    }
+
+    pub fn get(&self) -> T {
+        unsafe { *self.value.get() }
+    }
+}
+
+impl<T: ParamValue> KernelParam for Param<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn set(&self, value: Option<&'static str>) -> Result<(), ParamError> {
+        let value = T::parse(value)?;
+        if !(self.validate)(&value) {
+            return Err(ParamError::InvalidValue);
+        }
+        unsafe { *self.value.get() = value };
+        Ok(())
+    }
+}
+
+/// Declares a kernel command line parameter.
+///
+/// ```ignore
+/// kernel_param! {
+///     /// Messages above this level are not printed
+///     pub static LOGLEVEL: u8 = 6, name = "loglevel", validate = |level| *level <= 7;
+/// }
+/// ```
+#[macro_export]
+macro_rules! kernel_param {
+    (
+        $(#[$meta:meta])*
+        $vis:vis static $ident:ident: $ty:ty = $default:expr, name = $name:literal
+        $(, validate = $validate:expr)? $(,)?;
+    ) => {
+        $(#[$meta])*
+        $vis static $ident: $crate::cmdline::Param<$ty> = $crate::cmdline::Param::new(
+            $name,
+            $default,
+            $crate::kernel_param!(@validate $($validate)?),
+        );
+
+        const _: () = {
+            #[used]
+            #[link_section = "kernel_params"]
+            static ENTRY: &'static dyn $crate::cmdline::KernelParam = &$ident;
+        };
+    };
+    (@validate $validate:expr) => { $validate };
+    (@validate) => { |_| true };
+}
+
+extern "C" {
+    // Defined by the linker for the `kernel_params` section
+    static __start_kernel_params: u8;
+    static __stop_kernel_params: u8;
+}
+
+/// Every parameter declared with [`kernel_param!`].
+pub fn params() -> &'static [&'static dyn KernelParam] {
+    unsafe {
+        let start = core::ptr::addr_of!(__start_kernel_params).cast::<&'static dyn KernelParam>();
+        let stop = core::ptr::addr_of!(__stop_kernel_params).cast::<&'static dyn KernelParam>();
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+/// Sets the parameters given in `cmdline`.
+///
+/// Unknown parameters and invalid values are warned about and ignored,
+/// leaving the parameter at its default.
+pub fn parse(cmdline: &'static str) {
+    for arg in cmdline.split_whitespace() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        match params().iter().find(|param| param.name() == name) {
+            Some(param) => {
+                if let Err(err) = param.set(value) {
+                    crate::warn!("ignoring kernel parameter `{}`: {:?}", arg, err);
+                }
+            }
+            None => crate::warn!("unknown kernel parameter `{}`", name),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// Not every service is used by the kernel yet
+#![allow(dead_code)]
+
+use crate::boot_info::BootInfo;
+use core::ffi::c_void;
+use core::fmt;
+
+/// EFI_STATUS; errors have the high bit set.
+pub type Status = usize;
+
+const ERROR_BIT: Status = 1 << (usize::BITS - 1);
+
+type FnPtr = u64;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+/// EFI_TIME
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pad1: u8,
+    pub nanosecond: u32,
+    pub time_zone: i16,
+    pub daylight: u8,
+    pad2: u8,
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Keep in sync with bootloader/src/guid.rs and bootloader/src/nvram.rs
+
+/// Vendor GUID of the variables owned by lemola_os
+pub const LEMOLA_OS_VENDOR_GUID: Guid = Guid(
+    0xa5b89285,
+    0x96a7,
+    0x487c,
+    [0x97, 0xac, 0x26, 0x89, 0xa9, 0xff, 0xee, 0x90],
+);
+
+/// Title of the loader entry to boot next time only, as UTF-8
+pub const ONE_SHOT_ENTRY_VARIABLE: &[u16] = &ucs2(b"LemolaOneShotEntry\0");
+/// The loader reads at most this many bytes of the title
+pub const ONE_SHOT_ENTRY_MAX: usize = 64;
+
+pub const VARIABLE_NON_VOLATILE: u32 = 0x00000001;
+pub const VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x00000002;
+pub const VARIABLE_RUNTIME_ACCESS: u32 = 0x00000004;
+
+/// Widens an ASCII string to UCS-2 for variable names.
+const fn ucs2<const N: usize>(s: &[u8; N]) -> [u16; N] {
+    let mut chars = [0; N];
+    let mut i = 0;
+    while i < N {
+        chars[i] = s[i] as u16;
+        i += 1;
+    }
+    chars
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+pub enum ResetType {
+    Cold,
+    Warm,
+    Shutdown,
+}
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+/// EFI_RUNTIME_SERVICES after the loader switched them to virtual
+/// addressing. The firmware is not reentrant, so calls must not overlap.
+#[repr(C)]
+pub struct RuntimeServices {
+    hdr: TableHeader,
+    get_time: extern "efiapi" fn(time: *mut Time, capabilities: *mut c_void) -> Status,
+    set_time: FnPtr,
+    get_wakeup_time: FnPtr,
+    set_wakeup_time: FnPtr,
+    set_virtual_address_map: FnPtr,
+    convert_pointer: FnPtr,
+    get_variable: extern "efiapi" fn(
+        variable_name: *const u16,
+        vendor_guid: *const Guid,
+        attributes: *mut u32,
+        data_size: *mut usize,
+        data: *mut c_void,
+    ) -> Status,
+    get_next_variable_name: FnPtr,
+    set_variable: extern "efiapi" fn(
+        variable_name: *const u16,
+        vendor_guid: *const Guid,
+        attributes: u32,
+        data_size: usize,
+        data: *const c_void,
+    ) -> Status,
+    get_next_high_monotonic_count: FnPtr,
+    reset_system: extern "efiapi" fn(
+        reset_type: ResetType,
+        reset_status: Status,
+        data_size: usize,
+        reset_data: *const c_void,
+    ) -> !,
+}
+
+fn check(status: Status) -> Result<(), Status> {
+    if status & ERROR_BIT != 0 {
+        return Err(status);
+    }
+    Ok(())
+}
+
+impl RuntimeServices {
+    /// The table the loader handed over, if it could set up runtime
+    /// services.
+    pub fn get(boot_info: &BootInfo) -> Option<&'static Self> {
+        unsafe { (boot_info.runtime_services as *const Self).as_ref() }
+    }
+
+    pub fn get_time(&self) -> Result<Time, Status> {
+        let mut time = Time::default();
+        check((self.get_time)(&mut time, core::ptr::null_mut()))?;
+        Ok(time)
+    }
+
+    /// Reads the variable into `data` and returns its attributes and size.
+    ///
+    /// `name` must be NUL terminated.
+    pub fn get_variable(
+        &self,
+        name: &[u16],
+        vendor_guid: &Guid,
+        data: &mut [u8],
+    ) -> Result<(u32, usize), Status> {
+        assert_eq!(
+            name.last(),
+            Some(&0),
+            "variable name must be NUL terminated"
+        );
+        let mut attributes = 0;
+        let mut data_size = data.len();
+        check((self.get_variable)(
+            name.as_ptr(),
+            vendor_guid,
+            &mut attributes,
+            &mut data_size,
+            data.as_mut_ptr().cast(),
+        ))?;
+        Ok((attributes, data_size))
+    }
+
+    /// Writes the variable, or deletes it when `data` is empty. Only
+    /// variables with runtime access can be written after boot.
+    ///
+    /// `name` must be NUL terminated.
+    pub fn set_variable(
+        &self,
+        name: &[u16],
+        vendor_guid: &Guid,
+        attributes: u32,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        assert_eq!(
+            name.last(),
+            Some(&0),
+            "variable name must be NUL terminated"
+        );
+        check((self.set_variable)(
+            name.as_ptr(),
+            vendor_guid,
+            attributes,
+            data.len(),
+            data.as_ptr().cast(),
+        ))
+    }
+
+    /// Makes the loader boot the entry titled `title` on the next boot
+    /// only, e.g. right before `reset_system`. Titles longer than
+    /// `ONE_SHOT_ENTRY_MAX` bytes are ignored by the loader.
+    pub fn set_one_shot_entry(&self, title: &str) -> Result<(), Status> {
+        self.set_variable(
+            ONE_SHOT_ENTRY_VARIABLE,
+            &LEMOLA_OS_VENDOR_GUID,
+            VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS,
+            title.as_bytes(),
+        )
+    }
+
+    pub fn reset_system(&self, reset_type: ResetType) -> ! {
+        (self.reset_system)(reset_type, 0, 0, core::ptr::null())
+    }
+}
@@ -0,0 +1,101 @@
+use crate::cmdline::{ParamError, ParamValue};
+use crate::kernel_param;
+use crate::serial::SerialPort;
+use core::fmt::Write;
+
+/// syslog style levels; lower is more severe.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 3,
+    Warn = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Consoles {
+    pub serial: bool,
+    /// There is no frame buffer console yet; `fb` is accepted so that
+    /// command lines keep working once there is one.
+    pub frame_buffer: bool,
+}
+
+impl ParamValue for Consoles {
+    fn parse(value: Option<&'static str>) -> Result<Self, ParamError> {
+        let mut consoles = Consoles {
+            serial: false,
+            frame_buffer: false,
+        };
+        for console in value.ok_or(ParamError::MissingValue)?.split(',') {
+            match console {
+                "serial" => consoles.serial = true,
+                "fb" => consoles.frame_buffer = true,
+                _ => return Err(ParamError::InvalidValue),
+            }
+        }
+        Ok(consoles)
+    }
+}
+
+kernel_param! {
+    /// Messages above this level are not printed (0: emergency .. 7: debug)
+    pub static LOGLEVEL: u8 = LogLevel::Info as u8, name = "loglevel", validate = |level| *level <= 7;
+}
+
+kernel_param! {
+    /// Where kernel messages go, e.g. `console=serial,fb`
+    pub static CONSOLE: Consoles = Consoles {
+        serial: true,
+        frame_buffer: false,
+    }, name = "console";
+}
+
+pub fn init() {
+    SerialPort::com1().init();
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    if CONSOLE.get().serial {
+        let _ = SerialPort::com1().write_fmt(args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: core::fmt::Arguments) {
+    if level as u8 <= LOGLEVEL.get() {
+        _print(format_args!("[{:?}] {}\n", level, args));
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::logger::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::logger::_log($crate::logger::LogLevel::Error, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::logger::_log($crate::logger::LogLevel::Warn, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::logger::_log($crate::logger::LogLevel::Info, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::logger::_log($crate::logger::LogLevel::Debug, format_args!($($arg)*)));
+}
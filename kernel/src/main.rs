@@ -3,15 +3,52 @@
 use core::arch::asm;
 use core::panic::PanicInfo;
 
+mod boot_info;
+mod cmdline;
+mod efi;
+mod logger;
+mod serial;
+
+use boot_info::{BootInfo, EntropySource};
+use efi::RuntimeServices;
+
+kernel_param! {
+    /// Path of the first user process
+    static INIT: &'static str = "/init", name = "init";
+}
+
+kernel_param! {
+    /// Only bring up the bootstrap processor
+    static NOSMP: bool = false, name = "nosmp";
+}
+
 #[no_mangle]
-extern "C" fn kernel_main() {
+extern "C" fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    logger::init();
+    cmdline::parse(boot_info.cmdline());
+    info!("cmdline: {}", boot_info.cmdline());
+    debug!("init: {}, nosmp: {}", INIT.get(), NOSMP.get());
+    debug!("kernel slide: {:#x}", boot_info.kernel_slide);
+    if boot_info.rng_seed_source == EntropySource::None {
+        warn!("no entropy from the loader");
+    }
+    debug!(
+        "stack: {:#x}..{:#x}",
+        boot_info.kernel_stack.bottom, boot_info.kernel_stack.top
+    );
+    match RuntimeServices::get(boot_info).map(RuntimeServices::get_time) {
+        Some(Ok(time)) => info!("time: {}", time),
+        Some(Err(status)) => warn!("GetTime failed: {:#x}", status),
+        None => warn!("no UEFI runtime services"),
+    }
     loop {
         unsafe { asm!("hlt") };
     }
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    error!("{}", info);
     loop {
         unsafe { asm!("hlt") };
     }
@@ -0,0 +1,61 @@
+use core::arch::asm;
+
+const COM1: u16 = 0x3f8;
+
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+unsafe fn inb(port: u16) -> u8 {
+    let value;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// 16550 UART on COM1, 115200 baud 8N1.
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn com1() -> Self {
+        Self { base: COM1 }
+    }
+
+    pub fn init(&self) {
+        unsafe {
+            // Disable interrupts
+            outb(self.base + 1, 0x00);
+            // Set the divisor to 1 (115200 baud)
+            outb(self.base + 3, 0x80);
+            outb(self.base, 0x01);
+            outb(self.base + 1, 0x00);
+            // 8 bits, no parity, one stop bit
+            outb(self.base + 3, 0x03);
+            // Enable and clear the FIFOs
+            outb(self.base + 2, 0xc7);
+            // DTR, RTS and OUT2
+            outb(self.base + 4, 0x0b);
+        }
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        unsafe {
+            // Wait for the transmit holding register to be empty
+            while inb(self.base + 5) & 0x20 == 0 {}
+            outb(self.base, byte);
+        }
+    }
+}
+
+impl core::fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}